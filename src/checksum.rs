@@ -0,0 +1,46 @@
+//! Checksums of memory regions, as used by integrity fields in Agon binaries.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Sum of the bytes, modulo 256
+    Sum8,
+    /// CRC-32 as used by zip and PNG (reflected, polynomial $edb88320)
+    Crc32,
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "sum8" => Some(Algorithm::Sum8),
+            "crc32" => Some(Algorithm::Crc32),
+            _ => None
+        }
+    }
+
+    /// Bytes the checksum takes in memory, stored little-endian
+    pub fn len(self) -> u32 {
+        match self {
+            Algorithm::Sum8 => 1,
+            Algorithm::Crc32 => 4,
+        }
+    }
+
+    pub fn compute(self, data: &[u8]) -> u32 {
+        match self {
+            Algorithm::Sum8 => data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) as u32,
+            Algorithm::Crc32 => !data.iter().fold(!0u32, |crc, &b| {
+                (0..8).fold(crc ^ b as u32, |crc, _| {
+                    if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 }
+                })
+            }),
+        }
+    }
+}
+
+#[test]
+fn test_compute() {
+    assert_eq!(Algorithm::Sum8.compute(&[0x80, 0x80, 0x05]), 0x05);
+    assert_eq!(Algorithm::Crc32.compute(b"123456789"), 0xcbf43926);
+    assert_eq!(Algorithm::Crc32.compute(&[]), 0);
+    assert_eq!(Algorithm::from_name("crc16"), None);
+}
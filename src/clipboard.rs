@@ -0,0 +1,29 @@
+//! Copying text to the system clipboard, through whichever of the usual
+//! clipboard tools is installed.
+
+use std::io::Write;
+use std::process::{ Command, Stdio };
+
+/// Clipboard tools to try in turn, with their arguments
+const TOOLS: &[(&str, &[&str])] = &[
+    ("pbcopy", &[]),
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+    ("clip.exe", &[]),
+];
+
+/// Copy `text` to the clipboard, or say why it could not be
+pub fn copy(text: &str) -> Result<(), String> {
+    for (tool, args) in TOOLS {
+        let Ok(mut child) = Command::new(tool).args(*args)
+            .stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null()).spawn() else { continue };
+        let written = child.stdin.take().map(|mut stdin| stdin.write_all(text.as_bytes()));
+        return match (written, child.wait()) {
+            (Some(Ok(())), Ok(status)) if status.success() => Ok(()),
+            _ => Err(format!("{} failed (is there a display?)", tool)),
+        };
+    }
+    Err(format!("no clipboard tool found (tried {})",
+                TOOLS.iter().map(|(tool, _)| *tool).collect::<Vec<_>>().join(", ")))
+}
@@ -0,0 +1,741 @@
+//! Client-side formatting of the emulator's disassembly text.
+
+use std::collections::{ BTreeMap, BTreeSet };
+use std::io::Write;
+use crate::parser::number_from_str;
+use crate::settings::{ DisasmBytes, DisasmCase, DisasmSyntax, Settings };
+
+/// One disassembled instruction
+#[derive(Clone)]
+pub struct Line {
+    pub loc: u32,
+    pub asm: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Client-side options for a single disassembly listing
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DisasmOptions {
+    /// Collapse runs of filler bytes (eg: nop or rst $38) into one line
+    pub skip_padding: bool,
+    /// Emit source that can be fed back into an assembler
+    pub reassemblable: bool,
+    /// One JSON object per instruction, for frontends
+    pub json: bool,
+    /// Show each instruction's bytes as hex and ASCII, like a memory dump
+    pub mixed: bool,
+    /// Name the MOS calls made with rst
+    pub mos: bool,
+    /// How many levels of jump and call targets to list after the listing
+    pub follow: u32,
+    /// Just the address and instruction, for diffing listings
+    pub bare: bool,
+    /// Just the instruction, so that diffs show inserted code as such
+    pub nolocs: bool,
+    /// Split the listing into basic blocks, each with a header
+    pub blocks: bool,
+    /// Copy the listing to the clipboard rather than printing it
+    pub clip: bool,
+}
+
+/// Shortest run of filler bytes that skip-padding collapses
+const MIN_PADDING_RUN: usize = 4;
+
+/// Longest eZ80 instruction, including any .lil/.sis prefix, in bytes
+const MAX_INSTRUCTION_LEN: usize = 6;
+
+pub fn print_listing(pc: u32, adl: bool, lines: &[Line], settings: &Settings, options: &DisasmOptions) {
+    let _ = write_listing(&mut std::io::stdout(), pc, adl, lines, settings, options);
+}
+
+pub fn write_listing(out: &mut impl Write, pc: u32, adl: bool, lines: &[Line], settings: &Settings,
+                     options: &DisasmOptions) -> std::io::Result<()> {
+    if options.reassemblable {
+        for line in reassemblable_listing(adl, lines, settings) {
+            writeln!(out, "{}", line)?;
+        }
+        return Ok(());
+    }
+    if options.json {
+        for inst in lines {
+            writeln!(out, "{}", json_line(inst, pc, settings))?;
+        }
+        return Ok(());
+    }
+    if options.bare || options.nolocs {
+        for inst in lines {
+            let asm = bare_instruction(&format_instruction(&inst.asm, settings));
+            if options.nolocs {
+                writeln!(out, "{}", asm)?;
+            } else {
+                writeln!(out, "{}: {}", settings.address(inst.loc, adl), asm)?;
+            }
+        }
+        return Ok(());
+    }
+    writeln!(out, "\t.assume adl={}", if adl {1} else {0})?;
+    let blocks = if options.blocks { block_starts(lines) } else { BTreeMap::new() };
+    let mut i = 0;
+    while i < lines.len() {
+        if let Some(sources) = blocks.get(&i) {
+            if i > 0 {
+                writeln!(out)?;
+            }
+            let sources: Vec<String> = sources.iter().map(|&s| settings.address(s, adl)).collect();
+            if sources.is_empty() {
+                writeln!(out, "; block {}", settings.address(lines[i].loc, adl))?;
+            } else {
+                writeln!(out, "; block {} <- {}", settings.address(lines[i].loc, adl), sources.join(", "))?;
+            }
+        }
+        if options.skip_padding {
+            if let Some((num_lines, num_bytes, filler)) = padding_run(&lines[i..], pc) {
+                if num_bytes >= MIN_PADDING_RUN {
+                    writeln!(out, "  {}: ... {} bytes of ${:02x} ...", settings.address(lines[i].loc, adl), num_bytes, filler)?;
+                    i += num_lines;
+                    continue;
+                }
+            }
+        }
+        let inst = &lines[i];
+        let loc = settings.address(inst.loc, adl);
+        let marker = match (inst.loc == pc, settings.pc_arrow) {
+            (true, true) => "=>",
+            (true, false) => "*",
+            (false, true) => "  ",
+            (false, false) => " ",
+        };
+        let mut asm = format_instruction(&inst.asm, settings);
+        if options.mos {
+            let prev = i.checked_sub(1).map(|p| lines[p].asm.as_str());
+            if let Some(note) = crate::mos::annotation(prev, &inst.asm) {
+                asm = format!("{}  ; {}", asm, note);
+            }
+        }
+        let bytes: Vec<String> = inst.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        if options.mixed {
+            writeln!(out, "{} {}: {:width$} |{:ascii_width$}| {}", marker, loc, bytes.join(" "),
+                     ascii(&inst.bytes), asm, width = MAX_INSTRUCTION_LEN * 3 - 1, ascii_width = MAX_INSTRUCTION_LEN)?;
+            i += 1;
+            continue;
+        }
+        match settings.disasm_bytes {
+            DisasmBytes::On => writeln!(out, "{} {}: {:20} | {}", marker, loc, asm, bytes.join(" "))?,
+            DisasmBytes::Off => writeln!(out, "{} {}: {}", marker, loc, asm)?,
+            DisasmBytes::Left => writeln!(out, "{} {}: {:width$}  {}", marker, loc, bytes.join(" "), asm,
+                                          width = MAX_INSTRUCTION_LEN * 3 - 1)?,
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+/// The basic blocks of a listing, by the index of their first line, with
+/// the addresses of the instructions in the listing that branch to each.
+/// A block ends after a jump or return, and starts at any jump or call
+/// target; calls and rsts don't end a block, as they come back after it.
+fn block_starts(lines: &[Line]) -> BTreeMap<usize, Vec<u32>> {
+    let mut blocks: BTreeMap<usize, Vec<u32>> = BTreeMap::new();
+    if !lines.is_empty() {
+        blocks.insert(0, vec![]);
+    }
+    for (i, inst) in lines.iter().enumerate() {
+        if matches!(decode(&inst.asm).kind, InstructionKind::Jump | InstructionKind::Return) && i + 1 < lines.len() {
+            blocks.entry(i + 1).or_default();
+        }
+        if let Some(target) = branch_target(&inst.asm) {
+            if let Some(t) = lines.iter().position(|l| l.loc == target) {
+                blocks.entry(t).or_default().push(inst.loc);
+            }
+        }
+    }
+    blocks
+}
+
+/// Most bytes shown on one db line of a data range
+const DATA_LINE_LEN: usize = 8;
+
+/// Lines showing the bytes of a data range as db directives, with runs of
+/// printable characters as strings
+pub fn data_lines(start: u32, bytes: &[u8]) -> Vec<Line> {
+    bytes.chunks(DATA_LINE_LEN).enumerate().map(|(i, chunk)| {
+        let mut items: Vec<String> = vec![];
+        let mut text = String::new();
+        for &b in chunk {
+            if (0x20..0x7f).contains(&b) && b != b'"' {
+                text.push(b as char);
+                continue;
+            }
+            if !text.is_empty() {
+                items.push(format!("\"{}\"", std::mem::take(&mut text)));
+            }
+            items.push(format!("${:02x}", b));
+        }
+        if !text.is_empty() {
+            items.push(format!("\"{}\"", text));
+        }
+        Line { loc: start + (i * DATA_LINE_LEN) as u32, asm: format!("db {}", items.join(",")), bytes: chunk.to_vec() }
+    }).collect()
+}
+
+/// An instruction with its whitespace normalized to a single space after
+/// the mnemonic, and none between operands
+fn bare_instruction(asm: &str) -> String {
+    match asm.trim().split_once(char::is_whitespace) {
+        Some((mnemonic, operands)) => format!("{} {}", mnemonic, operands.split_whitespace().collect::<String>()),
+        None => asm.trim().to_string(),
+    }
+}
+
+/// Bytes as ASCII, with unprintable ones as spaces as in memory dumps
+pub fn ascii(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { ' ' }).collect()
+}
+
+/// Bytes used to pad out code: nop and rst $38
+const FILLER_BYTES: [u8; 2] = [0x00, 0xff];
+
+/// The filler byte of a single-byte nop or rst $38. Longer instructions are
+/// never filler, even when they repeat one byte value, eg: ld bc,$010101.
+fn filler_byte(inst: &Line) -> Option<u8> {
+    match inst.bytes[..] {
+        [b] if FILLER_BYTES.contains(&b) => Some(b),
+        _ => None
+    }
+}
+
+/// Count the leading instructions that are the same filler byte,
+/// returning (instructions, bytes, filler). The instruction at PC is never
+/// treated as padding.
+fn padding_run(lines: &[Line], pc: u32) -> Option<(usize, usize, u8)> {
+    let filler = filler_byte(lines.first()?)?;
+    let run: Vec<&Line> = lines.iter()
+        .take_while(|inst| inst.loc != pc && filler_byte(inst) == Some(filler))
+        .collect();
+    if run.is_empty() {
+        return None;
+    }
+    Some((run.len(), run.iter().map(|inst| inst.bytes.len()).sum(), filler))
+}
+
+/// Assembler source for the listing: an .org directive, and a label at
+/// each jump or call target inside the listing, which the branches refer to
+/// instead of the raw address.
+fn reassemblable_listing(adl: bool, lines: &[Line], settings: &Settings) -> Vec<String> {
+    let locs: BTreeSet<u32> = lines.iter().map(|inst| inst.loc).collect();
+    let targets: BTreeSet<u32> = lines.iter()
+        .filter_map(|inst| branch_target(&inst.asm))
+        .filter(|target| locs.contains(target))
+        .collect();
+    let label = |addr: u32| format!("L_{:06X}", addr);
+
+    let mut out = vec![format!("\t.assume adl={}", if adl {1} else {0})];
+    if let Some(first) = lines.first() {
+        out.push(format!("\t.org {}", reformat(&format!("${:06x}", first.loc), settings.disasm_syntax)));
+    }
+    for inst in lines {
+        if targets.contains(&inst.loc) {
+            out.push(format!("{}:", label(inst.loc)));
+        }
+        let asm = format_instruction(&inst.asm, settings);
+        match branch_target(&inst.asm) {
+            Some(target) if targets.contains(&target) => {
+                out.push(format!("\t{}", replace_last_operand(&asm, &label(target))));
+            }
+            _ => out.push(format!("\t{}", asm)),
+        }
+    }
+    out
+}
+
+/// How an instruction affects the flow of control
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InstructionKind { Jump, Call, Return, Other }
+
+impl InstructionKind {
+    fn name(self) -> &'static str {
+        match self {
+            InstructionKind::Jump => "jump",
+            InstructionKind::Call => "call",
+            InstructionKind::Return => "return",
+            InstructionKind::Other => "other",
+        }
+    }
+}
+
+/// An instruction split into the parts a frontend needs, so that it does
+/// not have to parse the disassembly text itself
+#[derive(Debug, PartialEq)]
+pub struct Decoded {
+    pub mnemonic: String,
+    pub operands: Vec<String>,
+    pub kind: InstructionKind,
+    /// Destination of a jump or call with an immediate operand
+    pub target: Option<u32>,
+}
+
+pub fn decode(asm: &str) -> Decoded {
+    let mnemonic = mnemonic(asm);
+    let operands = match asm.trim().split_once(char::is_whitespace) {
+        Some((_, operands)) => operands.split(',').map(|op| op.trim().to_string()).collect(),
+        None => vec![],
+    };
+    let kind = match mnemonic.as_str() {
+        "jp" | "jr" | "djnz" => InstructionKind::Jump,
+        "call" | "rst" => InstructionKind::Call,
+        "ret" | "reti" | "retn" => InstructionKind::Return,
+        _ => InstructionKind::Other,
+    };
+    Decoded { target: branch_target(asm), mnemonic, operands, kind }
+}
+
+/// Whether the instruction matches `pattern`: a mnemonic, optionally with
+/// operands, where * matches any one operand. Case is ignored.
+pub fn matches_instruction(asm: &str, pattern: &str) -> bool {
+    let inst = decode(asm);
+    let pattern = decode(pattern);
+    let same = |a: &str, b: &str| a.replace(' ', "").eq_ignore_ascii_case(&b.replace(' ', ""));
+    inst.mnemonic == pattern.mnemonic &&
+        (pattern.operands.is_empty() ||
+         (inst.operands.len() == pattern.operands.len() &&
+          inst.operands.iter().zip(&pattern.operands).all(|(op, p)| p == "*" || same(op, p))))
+}
+
+/// The instruction as a single-line JSON object
+fn json_line(inst: &Line, pc: u32, settings: &Settings) -> String {
+    let asm = format_instruction(&inst.asm, settings);
+    let decoded = decode(&asm);
+    let bytes: Vec<String> = inst.bytes.iter().map(|b| b.to_string()).collect();
+    let operands: Vec<String> = decoded.operands.iter().map(|op| json_string(op)).collect();
+    format!("{{\"loc\":{},\"pc\":{},\"bytes\":[{}],\"asm\":{},\"mnemonic\":{},\"operands\":[{}],\"kind\":\"{}\",\"target\":{}}}",
+            inst.loc,
+            inst.loc == pc,
+            bytes.join(","),
+            json_string(&asm),
+            json_string(&decoded.mnemonic),
+            operands.join(","),
+            decoded.kind.name(),
+            decoded.target.map_or("null".to_string(), |t| t.to_string()))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            ch if (ch as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Whether the instruction can change the flow of control
+pub fn is_branch(asm: &str) -> bool {
+    matches!(mnemonic(asm).as_str(),
+             "jp" | "jr" | "call" | "djnz" | "rst" | "ret" | "reti" | "retn")
+}
+
+/// Whether the instruction can execute again in place: a block instruction
+/// that repeats until BC runs out, or halt waiting for an interrupt
+pub fn repeats_in_place(asm: &str) -> bool {
+    matches!(mnemonic(asm).as_str(),
+             "ldir" | "lddr" | "cpir" | "cpdr" | "inir" | "indr" | "otir" | "otdr"
+             | "inirx" | "indrx" | "otirx" | "otdrx" | "ini2r" | "ind2r" | "oti2r" | "otd2r" | "halt")
+}
+
+/// The address a jp, jr, call or djnz with an immediate operand goes to
+pub fn branch_target(asm: &str) -> Option<u32> {
+    if !matches!(mnemonic(asm).as_str(), "jp" | "jr" | "call" | "djnz") {
+        return None;
+    }
+    let operands = asm.trim().split_once(char::is_whitespace)?.1;
+    immediate(operands.rsplit(',').next()?)
+}
+
+/// The value of an immediate operand, in any of the emulator's or the
+/// debugger's number notations
+pub fn immediate(operand: &str) -> Option<u32> {
+    let operand = operand.trim();
+    match operand.strip_prefix("0x").or(operand.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => number_from_str(operand),
+    }
+}
+
+/// Flag register bits tested by conditional branches
+const FLAG_S: u8 = 0x80;
+const FLAG_Z: u8 = 0x40;
+const FLAG_PV: u8 = 0x04;
+const FLAG_C: u8 = 0x01;
+
+/// Whether a conditional jp, jr, call, ret or djnz will branch, given the
+/// flags in F and, for djnz, the count in B. None if the instruction
+/// doesn't branch on a condition.
+pub fn branch_taken(asm: &str, f: u8, b: u8) -> Option<bool> {
+    let mnemonic = mnemonic(asm);
+    if mnemonic == "djnz" {
+        return Some(b.wrapping_sub(1) != 0);
+    }
+    let operands = asm.trim().split_once(char::is_whitespace)?.1;
+    let condition = match (mnemonic.as_str(), operands.split_once(',')) {
+        ("jp" | "jr" | "call", Some((condition, _))) => condition,
+        ("ret", None) => operands,
+        _ => return None,
+    };
+    let set = |flag: u8| f & flag != 0;
+    match condition.trim().to_ascii_lowercase().as_str() {
+        "nz" => Some(!set(FLAG_Z)),
+        "z" => Some(set(FLAG_Z)),
+        "nc" => Some(!set(FLAG_C)),
+        "c" => Some(set(FLAG_C)),
+        "po" => Some(!set(FLAG_PV)),
+        "pe" => Some(set(FLAG_PV)),
+        "p" => Some(!set(FLAG_S)),
+        "m" => Some(set(FLAG_S)),
+        _ => None,
+    }
+}
+
+/// The address a call or rst goes to
+pub fn call_target(asm: &str) -> Option<u32> {
+    match mnemonic(asm).as_str() {
+        "call" => branch_target(asm),
+        "rst" => immediate(asm.trim().split_once(char::is_whitespace)?.1),
+        _ => None
+    }
+}
+
+/// The absolute memory address an instruction reads or writes, as in
+/// `ld a,($40000)`, and whether it moves a register pair (a word) rather
+/// than a byte. I/O port operands are not memory and give None.
+pub fn memory_operand(asm: &str) -> Option<(u32, bool)> {
+    let inst = decode(asm);
+    if inst.mnemonic.starts_with("in") || inst.mnemonic.starts_with("out") {
+        return None;
+    }
+    let addr = inst.operands.iter()
+        .find_map(|op| op.strip_prefix('(')?.strip_suffix(')').and_then(immediate))?;
+    let word = inst.operands.iter().any(|op| {
+        matches!(op.to_ascii_lowercase().as_str(), "bc" | "de" | "hl" | "sp" | "ix" | "iy")
+    });
+    Some((addr, word))
+}
+
+fn replace_last_operand(asm: &str, operand: &str) -> String {
+    match asm.rfind(',').or(asm.trim_end().find(char::is_whitespace)) {
+        Some(i) => format!("{}{}", &asm[..=i], operand),
+        None => asm.to_string()
+    }
+}
+
+/// The instruction's mnemonic, lower-cased and without any eZ80 suffix
+/// (eg: "call" for "CALL.LIL $40000")
+pub fn mnemonic(asm: &str) -> String {
+    let word = asm.split_whitespace().next().unwrap_or("");
+    word.split('.').next().unwrap_or("").to_ascii_lowercase()
+}
+
+/// An instruction as the settings ask for: in the 'disasm-syntax', then in
+/// the 'disasm-case'
+pub fn format_instruction(asm: &str, settings: &Settings) -> String {
+    change_case(&reformat(asm, settings.disasm_syntax), settings.disasm_case)
+}
+
+/// Change the case of an instruction's mnemonic and registers, leaving
+/// hex numbers and quoted strings as they are
+fn change_case(asm: &str, case: DisasmCase) -> String {
+    if case == DisasmCase::Keep {
+        return asm.to_string();
+    }
+    let chars: Vec<char> = asm.chars().collect();
+    let mut out = String::new();
+    let mut in_string = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch == '"' {
+            in_string = !in_string;
+        }
+        if !in_string && (i == 0 || !is_word_char(chars[i-1])) {
+            if let Some((_, len)) = hex_literal(&chars[i..]) {
+                out.extend(&chars[i..i + len]);
+                i += len;
+                continue;
+            }
+        }
+        out.push(match case {
+            _ if in_string => ch,
+            DisasmCase::Upper => ch.to_ascii_uppercase(),
+            DisasmCase::Lower => ch.to_ascii_lowercase(),
+            DisasmCase::Keep => ch,
+        });
+        i += 1;
+    }
+    out
+}
+
+/// Rewrite an instruction in the requested syntax. Hex numbers are
+/// recognised as $ff, 0xff or 0ffh; anything inside double quotes is
+/// left alone.
+pub fn reformat(asm: &str, syntax: DisasmSyntax) -> String {
+    if syntax == DisasmSyntax::Native {
+        return asm.to_string();
+    }
+    let chars: Vec<char> = asm.chars().collect();
+    let mut out = String::new();
+    let mut in_string = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch == '"' {
+            in_string = !in_string;
+        }
+        if !in_string && (i == 0 || !is_word_char(chars[i-1])) {
+            if let Some((digits, len)) = hex_literal(&chars[i..]) {
+                out.push_str(&format_hex(&digits, syntax));
+                i += len;
+                continue;
+            }
+        }
+        out.push(match syntax {
+            _ if in_string => ch,
+            DisasmSyntax::Zilog => ch.to_ascii_uppercase(),
+            DisasmSyntax::Gnu => ch.to_ascii_lowercase(),
+            DisasmSyntax::Native => ch,
+        });
+        i += 1;
+    }
+    out
+}
+
+fn is_word_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
+}
+
+/// If `chars` starts with a hex number, return its digits and its length
+/// in chars
+fn hex_literal(chars: &[char]) -> Option<(String, usize)> {
+    let digits_at = |start: usize| -> String {
+        chars.iter().skip(start).take_while(|c| c.is_ascii_hexdigit()).collect()
+    };
+    let ends_at = |pos: usize| !chars.get(pos).copied().is_some_and(is_word_char);
+
+    if chars[0] == '$' {
+        let digits = digits_at(1);
+        let len = digits.len() + 1;
+        if !digits.is_empty() && ends_at(len) {
+            return Some((digits, len));
+        }
+    } else if chars[0] == '0' && matches!(chars.get(1), Some('x' | 'X')) {
+        let digits = digits_at(2);
+        let len = digits.len() + 2;
+        if !digits.is_empty() && ends_at(len) {
+            return Some((digits, len));
+        }
+    } else if chars[0].is_ascii_digit() {
+        let digits = digits_at(0);
+        let len = digits.len() + 1;
+        if matches!(chars.get(digits.len()), Some('h' | 'H')) && ends_at(len) {
+            // drop the leading 0 that only serves to start the number with a digit
+            let significant = match digits.strip_prefix('0') {
+                Some(rest) if rest.starts_with(|c: char| c.is_ascii_alphabetic()) => rest.to_string(),
+                _ => digits
+            };
+            return Some((significant, len));
+        }
+    }
+    None
+}
+
+fn format_hex(digits: &str, syntax: DisasmSyntax) -> String {
+    match syntax {
+        DisasmSyntax::Zilog => {
+            let digits = digits.to_ascii_uppercase();
+            if digits.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                format!("0{}h", digits)
+            } else {
+                format!("{}h", digits)
+            }
+        }
+        DisasmSyntax::Gnu => format!("0x{}", digits.to_ascii_lowercase()),
+        DisasmSyntax::Native => format!("${}", digits),
+    }
+}
+
+#[test]
+fn test_padding_run() {
+    let line = |loc: u32, bytes: &[u8]| Line { loc, asm: String::new(), bytes: bytes.to_vec() };
+    let lines = [line(0, &[0xff]), line(1, &[0xff]), line(2, &[0xff]), line(3, &[0x00]), line(4, &[0xff])];
+    assert_eq!(padding_run(&lines, 0x100), Some((3, 3, 0xff)));
+    assert_eq!(padding_run(&lines, 1), Some((1, 1, 0xff)));
+    assert_eq!(padding_run(&lines, 0), None);
+    assert_eq!(padding_run(&[line(0, &[0x21, 0x00, 0x00])], 0x100), None);
+    assert_eq!(padding_run(&[line(0, &[0x3c]), line(1, &[0x3c])], 0x100), None);
+}
+
+#[test]
+fn test_padding_run_keeps_repeated_byte_instructions() {
+    let line = |loc: u32, bytes: &[u8]| Line { loc, asm: String::new(), bytes: bytes.to_vec() };
+    // ld bc,$010101 and ld hl,$212121 in ADL mode
+    assert_eq!(padding_run(&[line(0, &[0x01, 0x01, 0x01, 0x01])], 0x100), None);
+    assert_eq!(padding_run(&[line(0, &[0x21, 0x21, 0x21, 0x21])], 0x100), None);
+    let lines = [line(0, &[0x00]), line(1, &[0x00]), line(2, &[0x01, 0x00, 0x00, 0x00]), line(6, &[0x00])];
+    assert_eq!(padding_run(&lines, 0x100), Some((2, 2, 0x00)));
+}
+
+#[test]
+fn test_block_starts() {
+    let line = |loc: u32, asm: &str| Line { loc, asm: asm.to_string(), bytes: vec![] };
+    let lines = vec![
+        line(0x40000, "ld b,$10"),
+        line(0x40002, "dec a"),
+        line(0x40003, "jr nz,$40002"),
+        line(0x40005, "call $40009"),
+        line(0x40009, "ret"),
+        line(0x4000a, "nop"),
+    ];
+    let blocks = block_starts(&lines);
+    assert_eq!(blocks.into_iter().collect::<Vec<_>>(), vec![
+        (0, vec![]),
+        (1, vec![0x40003]),
+        (3, vec![]),
+        (4, vec![0x40005]),
+        (5, vec![]),
+    ]);
+}
+
+#[test]
+fn test_data_lines() {
+    let lines = data_lines(0x40100, b"Hello\r\n\0World");
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].asm, "db \"Hello\",$0d,$0a,$00");
+    assert_eq!(lines[1].loc, 0x40108);
+    assert_eq!(lines[1].asm, "db \"World\"");
+    assert_eq!(lines[1].bytes, b"World");
+}
+
+#[test]
+fn test_bare_instruction() {
+    assert_eq!(bare_instruction("  ld   a, (ix+5) "), "ld a,(ix+5)");
+    assert_eq!(bare_instruction("nop"), "nop");
+}
+
+#[test]
+fn test_reassemblable_listing() {
+    let line = |loc: u32, asm: &str| Line { loc, asm: asm.to_string(), bytes: vec![] };
+    let lines = [
+        line(0x40000, "ld b,$10"),
+        line(0x40002, "djnz $040002"),
+        line(0x40004, "jp nz,$040000"),
+        line(0x40008, "call $000020"),
+    ];
+    assert_eq!(reassemblable_listing(true, &lines, &Settings::default()), [
+        "\t.assume adl=1",
+        "\t.org $040000",
+        "L_040000:",
+        "\tld b,$10",
+        "L_040002:",
+        "\tdjnz L_040002",
+        "\tjp nz,L_040000",
+        "\tcall $000020",
+    ]);
+    // the case setting applies to output fed to an assembler too
+    let settings = Settings { disasm_case: DisasmCase::Upper, ..Settings::default() };
+    assert_eq!(reassemblable_listing(true, &lines[..2], &settings), [
+        "\t.assume adl=1",
+        "\t.org $040000",
+        "\tLD B,$10",
+        "L_040002:",
+        "\tDJNZ L_040002",
+    ]);
+    assert_eq!(branch_target("JP (HL)"), None);
+    assert_eq!(branch_target("call.lil 0x40000"), Some(0x40000));
+    assert_eq!(call_target("rst.lil $08"), Some(0x08));
+    assert_eq!(call_target("jp $40000"), None);
+}
+
+#[test]
+fn test_decode() {
+    let decoded = decode("JP.LIL NZ,$040000");
+    assert_eq!(decoded.mnemonic, "jp");
+    assert_eq!(decoded.operands, ["NZ", "$040000"]);
+    assert_eq!(decoded.kind, InstructionKind::Jump);
+    assert_eq!(decoded.target, Some(0x40000));
+    assert_eq!(decode("ret").operands, Vec::<String>::new());
+
+    let inst = Line { loc: 0x40000, asm: "ld a,\"\\\"".to_string(), bytes: vec![0x3e, 0x22] };
+    assert_eq!(json_line(&inst, 0x40000, &Settings::default()),
+               r#"{"loc":262144,"pc":true,"bytes":[62,34],"asm":"ld a,\"\\\"","mnemonic":"ld","operands":["a","\"\\\""],"kind":"other","target":null}"#);
+    let settings = Settings { disasm_syntax: DisasmSyntax::Gnu, disasm_case: DisasmCase::Upper, ..Settings::default() };
+    let inst = Line { loc: 0x40000, asm: "jp $040010".to_string(), bytes: vec![0xc3, 0x10, 0x00, 0x04] };
+    assert_eq!(json_line(&inst, 0x40000, &settings),
+               r#"{"loc":262144,"pc":true,"bytes":[195,16,0,4],"asm":"JP 0x040010","mnemonic":"jp","operands":["0x040010"],"kind":"jump","target":262160}"#);
+}
+
+#[test]
+fn test_branch_taken() {
+    assert_eq!(branch_taken("jr nz,$40010", 0x00, 0), Some(true));
+    assert_eq!(branch_taken("jr nz,$40010", 0x40, 0), Some(false));
+    assert_eq!(branch_taken("JP C,$40000", 0x01, 0), Some(true));
+    assert_eq!(branch_taken("call.lil pe,$40000", 0x00, 0), Some(false));
+    assert_eq!(branch_taken("ret m", 0x80, 0), Some(true));
+    assert_eq!(branch_taken("djnz $40000", 0, 1), Some(false));
+    assert_eq!(branch_taken("djnz $40000", 0, 0), Some(true));
+    assert_eq!(branch_taken("jp $40000", 0, 0), None);
+    assert_eq!(branch_taken("jp (hl)", 0, 0), None);
+    assert_eq!(branch_taken("ret", 0, 0), None);
+    assert_eq!(branch_taken("ld a,c", 0x01, 0), None);
+}
+
+#[test]
+fn test_memory_operand() {
+    assert_eq!(memory_operand("ld a,($40000)"), Some((0x40000, false)));
+    assert_eq!(memory_operand("ld ($0100),hl"), Some((0x100, true)));
+    assert_eq!(memory_operand("ld a,(hl)"), None);
+    assert_eq!(memory_operand("ld hl,$40000"), None);
+    assert_eq!(memory_operand("in a,($10)"), None);
+}
+
+#[test]
+fn test_matches_instruction() {
+    assert!(matches_instruction("HALT", "halt"));
+    assert!(matches_instruction("out (c),a", "OUT (C),*"));
+    assert!(matches_instruction("out (c),a", "out"));
+    assert!(!matches_instruction("out ($10),a", "out (c),*"));
+    assert!(matches_instruction("ld a,(ix+5)", "ld a, ( ix+5 )"));
+    assert!(!matches_instruction("ld a,b", "ld a"));
+}
+
+#[test]
+fn test_change_case() {
+    assert_eq!(change_case("ld a,$ff", DisasmCase::Upper), "LD A,$ff");
+    assert_eq!(change_case("LD HL,0x0a0b", DisasmCase::Lower), "ld hl,0x0a0b");
+    assert_eq!(change_case("db \"Hi\",$0D", DisasmCase::Lower), "db \"Hi\",$0D");
+    assert_eq!(change_case("Jp $40000", DisasmCase::Keep), "Jp $40000");
+}
+
+#[test]
+fn test_reformat() {
+    assert_eq!(reformat("ld a,$ff", DisasmSyntax::Native), "ld a,$ff");
+    assert_eq!(reformat("ld a,$ff", DisasmSyntax::Zilog), "LD A,0FFh");
+    assert_eq!(reformat("LD HL,($040a12)", DisasmSyntax::Gnu), "ld hl,(0x040a12)");
+    assert_eq!(reformat("jp 0C000h", DisasmSyntax::Gnu), "jp 0xc000");
+    assert_eq!(reformat("ex af,af'", DisasmSyntax::Zilog), "EX AF,AF'");
+    assert_eq!(reformat("bit 3,(ix+12)", DisasmSyntax::Zilog), "BIT 3,(IX+12)");
+}
+
+#[test]
+fn test_write_listing() {
+    let lines = vec![
+        Line { loc: 0x40000, asm: "ld a,$10".to_string(), bytes: vec![0x3e, 0x10] },
+        Line { loc: 0x40002, asm: "ret".to_string(), bytes: vec![0xc9] },
+    ];
+    let options = DisasmOptions { nolocs: true, ..Default::default() };
+    let mut out = Vec::new();
+    write_listing(&mut out, 0x40000, true, &lines, &Settings::default(), &options).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "ld a,$10\nret\n");
+}
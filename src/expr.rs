@@ -0,0 +1,258 @@
+//! Arithmetic expressions for command arguments, eg: `[$40000] + [$40001] * 256`.
+//! Numbers use the same notations as elsewhere in the debugger,
+//! `[address]` reads the byte at that address and `$name` is a debugger
+//! variable.
+
+use std::collections::HashMap;
+use crate::parser::number_from_str;
+
+/// What an expression can refer to while being evaluated
+pub trait Env {
+    fn read_byte(&mut self, addr: u32) -> Result<u8, String>;
+    fn variable(&self, name: &str) -> Option<u32>;
+}
+
+/// Debugger variables, set with `$name = <expression>`. `$` on its own is
+/// the last value printed or assigned.
+#[derive(Default)]
+pub struct Variables {
+    values: HashMap<String, u32>,
+    last: Option<u32>,
+}
+
+impl Variables {
+    pub fn get(&self, name: &str) -> Option<u32> {
+        if name.is_empty() {
+            self.last
+        } else {
+            self.values.get(name).copied()
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: u32) -> Result<(), String> {
+        if !is_variable_name(name) {
+            return Err(format!("Invalid variable name '${}': names start with a letter, \
+                                and must not also be a hex number", name));
+        }
+        self.values.insert(name.to_string(), value);
+        self.last = Some(value);
+        Ok(())
+    }
+
+    pub fn set_last(&mut self, value: u32) {
+        self.last = Some(value);
+    }
+
+    /// All variables, sorted by name
+    pub fn sorted(&self) -> Vec<(&str, u32)> {
+        let mut vars: Vec<(&str, u32)> = self.values.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+        vars.sort();
+        vars
+    }
+}
+
+/// Variable names must not be valid hex, or `$name` would be ambiguous
+fn is_variable_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') &&
+        u32::from_str_radix(name, 16).is_err()
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum BinOp { Add, Sub, Mul, Div, Rem, And, Or, Xor, Shl, Shr }
+
+#[derive(Debug)]
+pub enum Expr {
+    Num(u32),
+    Var(String),
+    Deref(Box<Expr>),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+/// Binary operators, from lowest to highest precedence
+const PRECEDENCE: &[&[(&str, BinOp)]] = &[
+    &[("|", BinOp::Or)],
+    &[("^", BinOp::Xor)],
+    &[("&", BinOp::And)],
+    &[("<<", BinOp::Shl), (">>", BinOp::Shr)],
+    &[("+", BinOp::Add), ("-", BinOp::Sub)],
+    &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+];
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut p = ExprParser { text, pos: 0 };
+        let expr = p.parse_binary(0)?;
+        p.skip_whitespace();
+        if p.pos < text.len() {
+            return Err(format!("Unexpected '{}' in expression", p.rest()));
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, env: &mut impl Env) -> Result<u32, String> {
+        Ok(match self {
+            Expr::Num(n) => *n,
+            Expr::Var(name) => env.variable(name).ok_or(format!("Undefined variable ${}", name))?,
+            Expr::Deref(addr) => {
+                let addr = addr.eval(env)?;
+                env.read_byte(addr)? as u32
+            }
+            Expr::Neg(e) => e.eval(env)?.wrapping_neg(),
+            Expr::Not(e) => !e.eval(env)?,
+            Expr::Binary(op, lhs, rhs) => {
+                let a = lhs.eval(env)?;
+                let b = rhs.eval(env)?;
+                match op {
+                    BinOp::Add => a.wrapping_add(b),
+                    BinOp::Sub => a.wrapping_sub(b),
+                    BinOp::Mul => a.wrapping_mul(b),
+                    BinOp::Div => a.checked_div(b).ok_or("Division by zero")?,
+                    BinOp::Rem => a.checked_rem(b).ok_or("Division by zero")?,
+                    BinOp::And => a & b,
+                    BinOp::Or => a | b,
+                    BinOp::Xor => a ^ b,
+                    BinOp::Shl => a.checked_shl(b).unwrap_or(0),
+                    BinOp::Shr => a.checked_shr(b).unwrap_or(0),
+                }
+            }
+        })
+    }
+}
+
+struct ExprParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), String> {
+        if self.eat(s) {
+            Ok(())
+        } else {
+            Err(format!("Expected '{}' in expression", s))
+        }
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+        let mut lhs = self.parse_binary(level + 1)?;
+        'operators: loop {
+            for &(s, op) in PRECEDENCE[level] {
+                if self.eat(s) {
+                    let rhs = self.parse_binary(level + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'operators;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.eat("-") {
+            Ok(Expr::Neg(Box::new(self.parse_unary()?)))
+        } else if self.eat("~") {
+            Ok(Expr::Not(Box::new(self.parse_unary()?)))
+        } else if self.eat("(") {
+            let e = self.parse_binary(0)?;
+            self.expect(")")?;
+            Ok(e)
+        } else if self.eat("[") {
+            let e = self.parse_binary(0)?;
+            self.expect("]")?;
+            Ok(Expr::Deref(Box::new(e)))
+        } else {
+            self.parse_number()
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Expr, String> {
+        let rest = self.rest();
+        let prefix = if rest.starts_with(['$', '&']) { 1 } else { 0 };
+        let len = prefix + rest[prefix..].bytes().take_while(|b| b.is_ascii_alphanumeric() || *b == b'_').count();
+        if rest.is_empty() {
+            return Err("Unexpected end of expression".to_string());
+        }
+        let word = &rest[..len];
+        if let Some(n) = number_from_str(word) {
+            self.pos += len;
+            Ok(Expr::Num(n))
+        } else if let Some(name) = word.strip_prefix('$').filter(|name| name.is_empty() || is_variable_name(name)) {
+            self.pos += len;
+            Ok(Expr::Var(name.to_string()))
+        } else {
+            Err(format!("Expected a number at '{}'", rest))
+        }
+    }
+}
+
+#[cfg(test)]
+struct TestEnv {
+    memory: Vec<u8>,
+    vars: Variables,
+}
+
+#[cfg(test)]
+impl Env for TestEnv {
+    fn read_byte(&mut self, addr: u32) -> Result<u8, String> {
+        self.memory.get(addr as usize).copied().ok_or("bad address".to_string())
+    }
+    fn variable(&self, name: &str) -> Option<u32> {
+        self.vars.get(name)
+    }
+}
+
+#[test]
+fn test_expr() {
+    let mut env = TestEnv { memory: vec![0x34, 0x12, 0xff], vars: Variables::default() };
+    env.vars.set("count", 2).unwrap();
+    env.vars.set_last(7);
+    let mut eval = |text: &str| Expr::parse(text)?.eval(&mut env);
+    assert_eq!(eval("1 + 2 * 3"), Ok(7));
+    assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+    assert_eq!(eval("$10 | 1 << 2"), Ok(0x14));
+    assert_eq!(eval("&ff & 0fh"), Ok(0xf));
+    assert_eq!(eval("[0]+[1]*256"), Ok(0x1234));
+    assert_eq!(eval("[[1] - $10]"), Ok(0xff));
+    assert_eq!(eval("-1"), Ok(0xffffffff));
+    assert_eq!(eval("1 / 0"), Err("Division by zero".to_string()));
+    assert!(eval("1 +").is_err());
+    assert!(eval("1 2").is_err());
+    assert!(eval("[5]").is_err());
+    assert_eq!(eval("$count * 2 + $"), Ok(11));
+    assert_eq!(eval("$cafe"), Ok(0xcafe));
+    assert!(eval("$nothing").is_err());
+}
+
+#[test]
+fn test_variable_names() {
+    let mut vars = Variables::default();
+    assert!(vars.set("ptr_2", 1).is_ok());
+    assert!(vars.set("beef", 1).is_err());
+    assert!(vars.set("2x", 1).is_err());
+    assert_eq!(vars.get(""), Some(1));
+}
@@ -0,0 +1,62 @@
+//! Struct layouts, for decoding memory into named fields.
+
+/// An unsigned little-endian field
+#[derive(Debug)]
+pub struct Field {
+    pub name: String,
+    pub size: u32,
+}
+
+#[derive(Debug)]
+pub struct Layout {
+    pub fields: Vec<Field>,
+}
+
+impl Layout {
+    /// Parse a field list such as `u16 x u16 y u8 flags`
+    pub fn parse(words: &[&str]) -> Result<Layout, String> {
+        if words.is_empty() || !words.len().is_multiple_of(2) {
+            return Err("Fields are written as <type> <name> pairs, eg: u16 x u8 flags".to_string());
+        }
+        let fields = words.chunks_exact(2).map(|pair| {
+            let size = type_size(pair[0]).ok_or(format!("Unknown field type: {} (use u8, u16, u24 or u32)", pair[0]))?;
+            Ok(Field { name: pair[1].to_string(), size })
+        }).collect::<Result<Vec<Field>, String>>()?;
+        Ok(Layout { fields })
+    }
+
+    pub fn size(&self) -> u32 {
+        self.fields.iter().map(|f| f.size).sum()
+    }
+
+    /// The value of each field in `data`, or None for fields that run past
+    /// its end
+    pub fn decode(&self, data: &[u8]) -> Vec<(&Field, Option<u32>)> {
+        let mut offset = 0;
+        self.fields.iter().map(|field| {
+            let bytes = data.get(offset..offset + field.size as usize);
+            offset += field.size as usize;
+            (field, bytes.map(|b| crate::settings::Endian::Little.word(b)))
+        }).collect()
+    }
+}
+
+fn type_size(name: &str) -> Option<u32> {
+    match name {
+        "u8" => Some(1),
+        "u16" => Some(2),
+        "u24" => Some(3),
+        "u32" => Some(4),
+        _ => None
+    }
+}
+
+#[test]
+fn test_layout() {
+    let layout = Layout::parse(&["u16", "x", "u24", "ptr", "u8", "flags"]).unwrap();
+    assert_eq!(layout.size(), 6);
+    let values: Vec<Option<u32>> = layout.decode(&[0x34, 0x12, 0x00, 0x00, 0x04]).into_iter().map(|(_, v)| v).collect();
+    assert_eq!(values, [Some(0x1234), Some(0x40000), None]);
+    assert!(Layout::parse(&["u16"]).is_err());
+    assert!(Layout::parse(&["i16", "x"]).is_err());
+}
@@ -1,15 +1,34 @@
-use std::sync::mpsc::{Sender, Receiver};
+use std::cell::{ Cell, RefCell };
+use std::collections::{ BTreeMap, BTreeSet, HashMap, VecDeque };
+use std::io::{ IsTerminal, Write };
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
 use rustyline::error::ReadlineError;
-use rustyline::DefaultEditor;
+use rustyline::{ Configurer, DefaultEditor };
 
+mod checksum;
+mod clipboard;
+mod disasm;
+mod expr;
+mod layout;
+mod lines;
+mod mos;
 mod parser;
+mod record;
+mod registers;
+mod settings;
+mod symbols;
+mod vdp;
 
-use agon_cpu_emulator::debugger::{ DebugResp, DebugCmd, Registers, Reg16 };
+use agon_cpu_emulator::debugger::{ DebugResp, DebugCmd, Registers, Reg16, Trigger };
+use settings::Settings;
+use expr::Variables;
 
 #[derive(Clone)]
 struct EmuState {
     pub in_debugger: std::sync::Arc<std::sync::atomic::AtomicBool>,
     pub emulator_shutdown: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    /// Set by Ctrl-C, for long-running commands to notice and stop
+    pub interrupted: std::sync::Arc<std::sync::atomic::AtomicBool>,
 }
 
 impl EmuState {
@@ -25,31 +44,202 @@ impl EmuState {
         self.emulator_shutdown.load(std::sync::atomic::Ordering::SeqCst)
     }
 
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    pub fn clear_interrupt(&self) {
+        self.interrupted.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+
     pub fn shutdown(&self) {
         self.emulator_shutdown.store(true, std::sync::atomic::Ordering::SeqCst);
         self.set_in_debugger(false);
     }
 }
 
+/// Debugger-side state for the lifetime of the REPL
+struct Session {
+    state: EmuState,
+    settings: Settings,
+    variables: Variables,
+    symbols: symbols::Symbols,
+    lines: lines::LineMap,
+    /// Memory read while paused, by (start, len), when mem-read-cache is on
+    memory_cache: RefCell<HashMap<(u32, u32), Vec<u8>>>,
+    /// Recent disassembly of ROM, oldest first
+    disasm_cache: RefCell<VecDeque<(DisasmKey, Vec<disasm::Line>)>>,
+    /// PC and ADL mode from the last CPU state seen while paused, until the
+    /// CPU runs again
+    cpu_position: Cell<Option<(u32, bool)>>,
+    logpoints: HashMap<u32, Logpoint>,
+    /// Address of the logpoint whose registers are expected next
+    pending_logpoint: Cell<Option<u32>>,
+    /// Addresses (start, end exclusive) of the last disassembly listing, for
+    /// dis to carry on from, until the CPU state is next shown
+    last_disassembly: Cell<Option<(u32, u32)>>,
+    /// Address ranges (start, end exclusive) that disassembly shows as data
+    data_ranges: Vec<(u32, u32)>,
+    /// Address ranges (start, end exclusive) shown as code by disassembly
+    /// while detect-smc is on, sorted and merged
+    code_ranges: RefCell<Vec<(u32, u32)>>,
+    /// Breakpoints set with 'after' or 'from', by address
+    conditional_breaks: HashMap<u32, ConditionalBreak>,
+    /// Address of the conditional breakpoint whose state is expected next
+    pending_break: Cell<Option<u32>>,
+    /// Set while the responses of an ignored breakpoint hit are arriving
+    ignoring_hit: Cell<bool>,
+    /// Set from a trigger's auto-continue message until the CPU continues,
+    /// so that its pause in between doesn't enter the debugger
+    auto_continuing: Cell<bool>,
+    /// Commands to send once the current response has been handled, such
+    /// as continuing after an ignored hit
+    follow_ups: RefCell<Vec<DebugCmd>>,
+    /// Offset from SP and word length of the stack bytes that stack-bytes
+    /// asked for beyond those sent with the CPU state, while they are read
+    pending_stack: Cell<Option<(u32, usize)>>,
+    /// Struct layouts for mem-struct, by name
+    layouts: HashMap<String, layout::Layout>,
+    /// When the CPU was last continued, until it next pauses
+    continued_at: Cell<Option<std::time::Instant>>,
+    /// Commands entered, oldest first, limited by 'history-size'
+    history: Vec<String>,
+    /// The session recording started by 'record', if any
+    recording: record::Recording,
+}
+
+/// What a cached disassembly was asked for
+#[derive(Clone, Copy, PartialEq)]
+enum DisasmKey {
+    /// Disassemble in the given mode from start to end
+    Range { adl: bool, start: u32, end: u32 },
+    /// DisassemblePc in the given mode, with the CPU at pc
+    AroundPc { adl: bool, pc: u32 },
+}
+
+/// A breakpoint that only pauses from its `after`th hit on, and only
+/// when called from `from`
+struct ConditionalBreak {
+    after: u32,
+    from: Option<u32>,
+    show_dis: bool,
+    hits: Cell<u32>,
+}
+
+/// A trigger that prints a message with register values, set by logpoint
+struct Logpoint {
+    message: String,
+    limit: Option<u32>,
+    hits: Cell<u32>,
+}
+
+impl Logpoint {
+    fn remaining(&self) -> Option<u32> {
+        self.limit.map(|limit| limit.saturating_sub(self.hits.get()))
+    }
+}
+
+impl Session {
+    /// Forget cached memory, and where the CPU is, which the CPU may be
+    /// about to change
+    fn invalidate_memory_cache(&self) {
+        self.memory_cache.borrow_mut().clear();
+        self.cpu_position.set(None);
+    }
+
+    /// Note where the CPU is from a response that says, while paused
+    fn note_cpu_position(&self, resp: &DebugResp) {
+        if let DebugResp::Registers(registers) | DebugResp::State { registers, .. } = resp {
+            if self.state.is_in_debugger() {
+                self.cpu_position.set(Some((registers.pc, registers.adl)));
+            }
+        }
+    }
+}
+
+/// Evaluates expressions against the live CPU
+struct CpuEnv<'a> {
+    tx: &'a Sender<DebugCmd>,
+    rx: &'a Receiver<DebugResp>,
+    session: &'a Session,
+}
+
+impl expr::Env for CpuEnv<'_> {
+    fn read_byte(&mut self, addr: u32) -> Result<u8, String> {
+        read_memory(addr, 1, self.tx, self.rx, self.session).and_then(|data| data.first().copied())
+            .ok_or(format!("Can not read ${:06x}", addr))
+    }
+
+    fn variable(&self, name: &str) -> Option<u32> {
+        self.session.variables.get(name)
+    }
+}
+
 fn print_help() {
     println!("While CPU is running:");
     println!("<CTRL-C>                     Pause Agon CPU and enter debugger");
+    println!("                             (or stop a command such as step-branch)");
     println!();
     println!("While CPU is paused:");
-    println!("br[eak] <address>            Set a breakpoint at the hex address");
+    println!("br[eak] <address ...> [after <n>] [from <caller>] [show dis] Set breakpoints (see help break)");
+    println!("br[eak] [pc][+|-<offset>]    Set a breakpoint at or relative to PC");
+    println!("break-adl-change             Step until the CPU switches between ADL and Z80 mode");
+    println!("break-depth <n>              Step until call depth exceeds <n> frames");
+    println!("break-op <instruction>       Step until an instruction such as halt is reached");
+    println!("clear, cls                   Clear the screen");
     println!("c[ontinue]                   Resume (un-pause) Agon CPU");
-    println!("delete <address>             Delete a breakpoint");
-    println!("dis[assemble] [start] [end]  Disassemble in current ADL mode");
+    println!("continue-to-interrupt        Step until an interrupt is serviced");
+    println!("delete [address ...]         Delete breakpoints (all if no address given)");
+    println!("dis[assemble] [start] [end]  Disassemble in current ADL mode (see help dis)");
+    println!("dis[assemble] [-]            Carry on from, or go back before, the last listing");
+    println!("dis-func                     Disassemble the function containing PC");
     println!("dis16 [start] [end]          Disassemble in ADL=0 (Z80) mode");
     println!("dis24 [start] [end]          Disassemble in ADL=1 (24-bit) mode");
+    println!("examine-stack-frame          Show the stack as a call frame (see help frame)");
     println!("exit                         Quit from Agon Light Emulator");
+    println!("find-string \"<text>\" [range] Search memory for ASCII text (see help find-string)");
+    println!("finish [calls]               Step until the current routine returns");
+    println!("fix-checksum <start> <len> <addr> [algo] Check a checksum field (see help fix-checksum)");
+    println!("help [command]               Show detailed help for a command");
+    println!("info all-registers           Show every register, with flags decoded");
     println!("info breakpoints             List breakpoints");
-    println!("[mem]ory <start> [len]       Dump memory");
+    println!("info cpu                     Summarize the CPU's mode and state");
+    println!("info data                    List the ranges disassembly shows as data");
+    println!("info line <address>          Show the source line of an address");
+    println!("info logpoints               List logpoints and their remaining hits");
+    println!("info skip                    List the ranges step steps over calls into");
+    println!("info variables               List debugger variables");
+    println!("info vdp [sysvars]           Show the video mode from MOS's system variables");
+    println!("[mem]ory <start> [len] [vdu] Dump memory, optionally naming VDU commands");
+    println!("load-lines <file>            Load source line information");
+    println!("load-symbols <file>          Load symbols from a symbol or map file");
+    println!("logpoint <addr> \"<msg>\" [limit <n>] Print registers at <addr> without pausing");
+    println!("mark-data <start> <end>      Show a range as data in disassembly");
+    println!("mem-struct <name> <addr> [n] Decode memory as a struct (see help struct)");
+    println!("mem-verify <file> <address>  Compare memory with a file");
+    println!("mem-wait <address> <value>   Resume until the byte at an address has a value");
     println!("n[ext]                       Step over function calls");
     println!("pause                        Pause execution and enter debugger");
-    println!("state                        Show CPU state");
+    println!("p[rint] <expression>         Print the value of an expression");
+    println!("record <file>|off            Record commands and CPU responses to a file");
+    println!("reg <register> [op] <value>  Check a register write (see help reg)");
+    println!("state [regs|full]            Show CPU state, without or with more of the stack");
     println!(".                            Show CPU state");
-    println!("s[tep]                       Execute one instuction");
+    println!("$name = <expression>         Set a debugger variable (see help print)");
+    println!("replay <file>                Run the commands in a recording again");
+    println!("save-state <file> [start] [len] Save registers, breakpoints, memory and history");
+    println!("set <name> <value>           Change a debugger setting (see help set)");
+    println!("show [name]                  Show the value of a setting, or of all settings");
+    println!("s[tep] [count]               Execute one instuction, or <count>");
+    println!("step-branch                  Step until a jump, call or return is taken");
+    println!("step-to-write <address>      Step until the byte at an address changes");
+    println!("struct define <name> ...     Define a struct layout for mem-struct");
+    println!("time <start> <end>           Count the instructions run from <start> to <end>");
+    println!("toggle <address>             Delete the breakpoint at <address>, or set one");
     println!("trace on                     Enable logging every instruction");
     println!("trace off                    Disable logging every instruction");
     println!("trigger <address> cmd1 : cmd2 : ...");
@@ -58,31 +248,1591 @@ fn print_help() {
     println!("        trigger $123 pause:\"CPU paused at breakpoint\":state");
     println!();
     println!("triggers                     List triggers");
+    println!("vdp-dump <file> <addr> <w> <h> Save bitmap data in memory as a PPM image");
+    println!("wait-break [timeout_ms]      Continue until a breakpoint is hit or time runs out");
+    println!("wait-idle [timeout_ms]       Continue until the CPU is stuck in a small loop");
+    println!("watch-expr <expression>      Step until the expression's value changes");
+    println!("watch-range <start> <end>    Step until any byte in a range changes");
+    println!("x <address> [count] [b|h|w]  Examine memory as bytes, halfwords or words");
     println!();
+    println!("Commands can be abbreviated to any unambiguous prefix (eg: disas, mem-v).");
     println!("The previous command can be repeated by pressing return.");
+    println!("!! runs the last command again, and !<text> the last one starting with <text>.");
 }
 
-fn do_cmd(cmd: parser::Cmd, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, state: &EmuState) {
+fn do_cmd(cmd: parser::Cmd, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    if let Some(question) = cmd.confirmation() {
+        if session.settings.confirm && !confirm(&question) {
+            return;
+        }
+    }
     match cmd {
+        parser::Cmd::Core(DebugCmd::Step) => step(1, tx, rx, session),
+        parser::Cmd::Core(DebugCmd::StepOver) => step_over(tx, rx, session),
+        parser::Cmd::Core(cmd @ (DebugCmd::Disassemble { .. } | DebugCmd::DisassemblePc { .. })) => {
+            let Some((pc, adl, mut lines)) = disassemble(cmd, tx, rx, session) else { return };
+            annotate_values(&mut lines, adl, tx, rx, session);
+            disasm::print_listing(pc, adl, &lines, &session.settings, &Default::default());
+            listed(&lines, session);
+        }
+        parser::Cmd::Core(DebugCmd::GetMemory { start, len }) => {
+            let Some(data) = read_memory(start, len, tx, rx, session) else { return };
+            print_memory_rows(start, &data);
+        }
         parser::Cmd::Core(debug_cmd) => {
-            tx.send(debug_cmd).unwrap();
-            handle_debug_resp(&rx.recv().unwrap(), state);
+            if matches!(debug_cmd, DebugCmd::Continue | DebugCmd::Step | DebugCmd::StepOver) {
+                session.invalidate_memory_cache();
+            }
+            if matches!(debug_cmd, DebugCmd::Continue) {
+                session.continued_at.set(Some(std::time::Instant::now()));
+            }
+            let _ = tx.send(debug_cmd);
+            if let Some(resp) = recv_response(rx, session) {
+                handle_debug_resp(&resp, session);
+            }
+            finish_stack(tx, rx, session);
+        }
+        parser::Cmd::UiHelp(None) => print_help(),
+        parser::Cmd::UiHelp(Some(text)) => println!("{}", text),
+        parser::Cmd::UiExit => session.state.shutdown(),
+        parser::Cmd::UiClear => {
+            // clear the screen and move the cursor to the top left
+            print!("\x1b[2J\x1b[H");
+            std::io::stdout().flush().unwrap();
+        }
+        parser::Cmd::UiSet(name, value) => {
+            session.invalidate_memory_cache();
+            session.disasm_cache.borrow_mut().clear();
+            if let Err(msg) = session.settings.set(&name, &value) {
+                println!("{}", msg);
+            }
+            if !session.settings.detect_smc {
+                session.code_ranges.borrow_mut().clear();
+            }
+        }
+        parser::Cmd::UiShow(Some(name)) => match session.settings.get(&name) {
+            Ok(value) => println!("{} {}", name, value),
+            Err(msg) => println!("{}", msg),
+        },
+        parser::Cmd::UiShow(None) => {
+            for &name in settings::NAMES {
+                println!("{:16} {}", name, session.settings.get(name).unwrap());
+            }
+        }
+        parser::Cmd::UiWatchExpr(expr) => watch_expr(&expr, tx, rx, session),
+        parser::Cmd::UiWatchRange { start, end } => watch_range(start, end, tx, rx, session),
+        parser::Cmd::UiStep(count) => step(count, tx, rx, session),
+        parser::Cmd::UiStepBranch => step_to_branch(tx, rx, session),
+        parser::Cmd::UiStepToWrite(address) => step_to_write(address, tx, rx, session),
+        parser::Cmd::UiBreakDepth(max_depth) => step_to_depth(max_depth, tx, rx, session),
+        parser::Cmd::UiBreakpoints(triggers) => {
+            let count = triggers.len();
+            for trigger in triggers {
+                do_cmd(parser::Cmd::Core(DebugCmd::AddTrigger(trigger)), tx, rx, session);
+            }
+            println!("Set {} breakpoints", count);
+        }
+        parser::Cmd::UiBreakAtPc { offset } => {
+            let Some((pc, adl)) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+                DebugResp::Registers(reg) => Ok((reg.pc, reg.adl)),
+                resp => Err(resp),
+            }) else { return };
+            let Ok(address) = u32::try_from(pc as i64 + offset) else {
+                println!("PC{:+} is below address 0", offset);
+                return;
+            };
+            println!("Breakpoint at ${}", session.settings.address(address, adl));
+            do_cmd(parser::Cmd::Core(DebugCmd::AddTrigger(parser::breakpoint(address, false))), tx, rx, session);
+        }
+        parser::Cmd::UiTime { start, end, timeout_ms } => time_region(start, end, timeout_ms, tx, rx, session),
+        parser::Cmd::UiToggle(address) => {
+            let Some(is_set) = request(DebugCmd::ListTriggers, tx, rx, session, |resp| match resp {
+                DebugResp::Triggers(ts) => Ok(ts.iter().any(|t| t.address == address)),
+                resp => Err(resp),
+            }) else { return };
+            if is_set {
+                session.logpoints.remove(&address);
+                session.conditional_breaks.remove(&address);
+                do_cmd(parser::Cmd::Core(DebugCmd::DeleteTrigger(address)), tx, rx, session);
+                println!("Deleted breakpoint at ${:06x}: now off", address);
+            } else {
+                do_cmd(parser::Cmd::Core(DebugCmd::AddTrigger(parser::breakpoint(address, false))), tx, rx, session);
+                println!("Set breakpoint at ${:06x}: now on", address);
+            }
+        }
+        parser::Cmd::UiConditionalBreak { addresses, after, from, show_dis } => {
+            for address in addresses {
+                session.conditional_breaks.insert(address, ConditionalBreak { after, from, show_dis, hits: Cell::new(0) });
+                // the tags bracket the hit's responses, so that they can be
+                // hidden for hits that don't meet the conditions
+                do_cmd(parser::Cmd::Core(DebugCmd::AddTrigger(Trigger {
+                    address,
+                    once: false,
+                    actions: vec![
+                        DebugCmd::Message(format!("{}{:06x}", BREAK_TAG, address)),
+                        DebugCmd::Pause,
+                        DebugCmd::GetState,
+                        DebugCmd::Message(BREAK_END_TAG.to_string()),
+                    ],
+                })), tx, rx, session);
+            }
+        }
+        parser::Cmd::UiBreakAdlChange => step_to_adl_change(tx, rx, session),
+        parser::Cmd::UiBreakOp(pattern) => step_to_instruction(&pattern, tx, rx, session),
+        parser::Cmd::UiWaitBreak(timeout_ms) => wait_for_break(timeout_ms, tx, rx, session),
+        parser::Cmd::UiFinish { stop_on_call } => finish(stop_on_call, tx, rx, session),
+        parser::Cmd::UiWaitIdle(timeout_ms) => wait_for_idle(timeout_ms, tx, rx, session),
+        parser::Cmd::UiContinueToInterrupt => step_to_interrupt(tx, rx, session),
+        parser::Cmd::UiStackFrame => print_stack_frame(tx, rx, session),
+        parser::Cmd::UiAssign(name, expr) => {
+            match expr.eval(&mut CpuEnv { tx, rx, session }) {
+                Ok(value) => {
+                    if let Err(msg) = session.variables.set(&name, value) {
+                        println!("{}", msg);
+                    }
+                }
+                Err(msg) => println!("{}", msg),
+            }
+        }
+        parser::Cmd::UiPrint(expr) => {
+            match expr.eval(&mut CpuEnv { tx, rx, session }) {
+                Ok(value) => {
+                    println!("${:x} ({})", value, value);
+                    session.variables.set_last(value);
+                }
+                Err(msg) => println!("{}", msg),
+            }
+        }
+        parser::Cmd::UiDelete(addrs) => delete_triggers(&addrs, tx, rx, session),
+        parser::Cmd::UiDeleteAll => {
+            let Some(addrs) = request::<Vec<u32>>(DebugCmd::ListTriggers, tx, rx, session, |resp| match resp {
+                DebugResp::Triggers(ts) => Ok(ts.iter().map(|t| t.address).collect()),
+                resp => Err(resp),
+            }) else { return };
+            if addrs.is_empty() {
+                println!("No breakpoints to delete");
+            } else if !session.settings.confirm || confirm(&format!("Delete all {} breakpoints?", addrs.len())) {
+                delete_triggers(&addrs, tx, rx, session);
+            }
+        }
+        parser::Cmd::UiDisassemble(cmd, options) => {
+            let Some((pc, adl, mut lines)) = disassemble(cmd, tx, rx, session) else { return };
+            if !(options.json || options.bare || options.nolocs) {
+                annotate_values(&mut lines, adl, tx, rx, session);
+            }
+            if options.clip {
+                copy_listing(pc, adl, &lines, &session.settings, &options);
+            } else {
+                disasm::print_listing(pc, adl, &lines, &session.settings, &options);
+            }
+            listed(&lines, session);
+            if options.follow > 0 {
+                let mut visited = BTreeSet::new();
+                follow_branches(&lines, adl, &mut visited, &options, tx, rx, session);
+            }
+        }
+        parser::Cmd::UiExamine { start, count, unit } => {
+            let Some(data) = read_memory(start, count * unit, tx, rx, session) else { return };
+            print_units(start, &data, unit as usize, session.settings.word_endian);
+        }
+        parser::Cmd::UiMemWait { address, value } => wait_for_value(address, value, tx, rx, session),
+        parser::Cmd::UiFindString { text, start, end, nocase, context } => {
+            let Some(data) = read_memory(start, end - start, tx, rx, session) else { return };
+            print_string_matches(&text, start, &data, nocase, context);
+        }
+        parser::Cmd::UiMemVerify { path, address } => {
+            match std::fs::read(&path) {
+                Ok(expected) if expected.len() > parser::ADDRESS_SPACE_LEN as usize => {
+                    println!("{} is {} bytes, more than the 16MB address space", path, expected.len());
+                }
+                Ok(expected) => {
+                    let Some(actual) = read_memory(address, expected.len() as u32, tx, rx, session) else { return };
+                    for line in verify_memory(address, &expected, &actual) {
+                        println!("{}", line);
+                    }
+                }
+                Err(e) => println!("Error reading {}: {}", path, e),
+            }
+        }
+        parser::Cmd::UiSetRegister(name, value) => {
+            let Some(adl) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+                DebugResp::Registers(reg) => Ok(reg.adl),
+                resp => Err(resp),
+            }) else { return };
+            match registers::validate(&name, value, adl) {
+                Ok(()) => println!("Can not write {}: the emulator's debugger interface has no register writes",
+                                   name.to_ascii_uppercase()),
+                Err(msg) => println!("{}", msg),
+            }
+        }
+        parser::Cmd::UiAdjustRegister(name, adjust, operand) => {
+            let Some(reg) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+                DebugResp::Registers(reg) => Ok(reg),
+                resp => Err(resp),
+            }) else { return };
+            match registers::width(&name, reg.adl).zip(registers::value(&reg, &name)) {
+                Some((bits, current)) => {
+                    let value = adjust.apply(current, operand, bits);
+                    println!("Can not write {} = ${:0digits$x} (was ${:0digits$x}): the emulator's debugger interface has no register writes",
+                             name.to_ascii_uppercase(), value, current, digits = bits as usize / 4);
+                }
+                None => println!("Unknown register: {}", name),
+            }
+        }
+        parser::Cmd::UiLoadSymbols(path) => {
+            match std::fs::read_to_string(&path) {
+                Ok(text) => {
+                    session.symbols = symbols::Symbols::parse(&text);
+                    println!("Loaded {} symbols from {}", session.symbols.len(), path);
+                }
+                Err(e) => println!("Error reading {}: {}", path, e),
+            }
+        }
+        parser::Cmd::UiRecord(Some(path)) => {
+            match record::Recorder::create(&path) {
+                Ok(recorder) => {
+                    *session.recording.lock().unwrap() = Some(recorder);
+                    println!("Recording to {}", path);
+                }
+                Err(e) => println!("Error writing {}: {}", path, e),
+            }
+        }
+        parser::Cmd::UiRecord(None) => {
+            if session.recording.lock().unwrap().take().is_some() {
+                println!("Recording stopped");
+            } else {
+                println!("Not recording");
+            }
+        }
+        parser::Cmd::UiReplay(path) => {
+            match std::fs::read_to_string(&path) {
+                Ok(text) => {
+                    for cmd in record::commands(&text) {
+                        if session.state.is_interrupted() {
+                            println!("Replay interrupted");
+                            break;
+                        }
+                        if !session.settings.input_echo {
+                            println!("{}{}", PROMPT, cmd);
+                        }
+                        eval_cmd(&cmd, tx, rx, session);
+                    }
+                }
+                Err(e) => println!("Error reading {}: {}", path, e),
+            }
+        }
+        parser::Cmd::UiLoadLines(path) => {
+            match std::fs::read_to_string(&path) {
+                Ok(text) => {
+                    session.lines = lines::LineMap::parse(&text);
+                    println!("Loaded {} line addresses from {}", session.lines.len(), path);
+                }
+                Err(e) => println!("Error reading {}: {}", path, e),
+            }
+        }
+        parser::Cmd::UiInfoLine(addr) => {
+            match session.lines.line_at(addr) {
+                Some((file, line)) => println!("${:06x} is in {}:{}", addr, file, line),
+                None => println!("No line information for ${:06x}", addr),
+            }
+        }
+        parser::Cmd::UiDisassembleFunction => {
+            let Some(pc) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+                DebugResp::Registers(reg) => Ok(reg.pc),
+                resp => Err(resp),
+            }) else { return };
+            match session.symbols.containing(pc) {
+                Some((start, name, next)) => {
+                    let end = next.unwrap_or(start.saturating_add(session.settings.listsize).min(0xffffff));
+                    println!("{}:", name);
+                    do_cmd(parser::Cmd::Core(DebugCmd::Disassemble { adl: None, start, end }), tx, rx, session);
+                }
+                None => println!("No symbol at or before PC ${:06x}", pc),
+            }
+        }
+        parser::Cmd::UiStructDefine(name, layout) => {
+            session.layouts.insert(name, layout);
+        }
+        parser::Cmd::UiLogpoint { address, message, limit } => {
+            session.logpoints.insert(address, Logpoint { message, limit, hits: Cell::new(0) });
+            do_cmd(parser::Cmd::Core(DebugCmd::AddTrigger(Trigger {
+                address,
+                once: false,
+                actions: vec![
+                    DebugCmd::Message(format!("{}{:06x}", LOGPOINT_TAG, address)),
+                    DebugCmd::GetRegisters,
+                ],
+            })), tx, rx, session);
+        }
+        parser::Cmd::UiAllRegisters => {
+            let Some(reg) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+                DebugResp::Registers(reg) => Ok(reg),
+                resp => Err(resp),
+            }) else { return };
+            registers::print_all(&reg);
+        }
+        parser::Cmd::UiRegistersVerbose => {
+            let Some(reg) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+                DebugResp::Registers(reg) => Ok(reg),
+                resp => Err(resp),
+            }) else { return };
+            registers::print_verbose(&reg);
+        }
+        parser::Cmd::UiVdpInfo(sysvars) => {
+            match sysvars.or_else(|| session.symbols.address("_sysvars")) {
+                Some(addr) => {
+                    let Some(sysvars) = read_memory(addr, vdp::SYSVARS_LEN, tx, rx, session) else { return };
+                    for line in vdp::describe_screen(&sysvars) {
+                        println!("{}", line);
+                    }
+                }
+                None => println!("info vdp <sysvars>: give the address of MOS's system variables, or load MOS's symbols"),
+            }
+        }
+        parser::Cmd::UiCpuInfo => {
+            let Some(reg) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+                DebugResp::Registers(reg) => Ok(reg),
+                resp => Err(resp),
+            }) else { return };
+            print_cpu_info(&reg, session);
+        }
+        parser::Cmd::UiMemoryVdu { start, len } => {
+            let Some(data) = read_memory(start, len, tx, rx, session) else { return };
+            let notes = vdp::vdu_annotations(&data);
+            let row_len = memory_row_len();
+            for (row, chunk) in data.chunks(row_len).enumerate() {
+                let offsets = row * row_len..row * row_len + chunk.len();
+                let names: Vec<&str> = notes.iter()
+                    .filter(|(offset, _)| offsets.contains(offset))
+                    .map(|(_, name)| name.as_str())
+                    .collect();
+                let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("{:06x}: {:width$} | {:ascii_width$} | {}", start + offsets.start as u32, hex.join(" "),
+                         disasm::ascii(chunk), names.join("; "), width = row_len * 3 - 1, ascii_width = row_len);
+            }
+        }
+        parser::Cmd::UiFixChecksum { start, len, field, algorithm } => {
+            let Some(data) = read_memory(start, len, tx, rx, session) else { return };
+            let Some(stored) = read_memory(field, algorithm.len(), tx, rx, session) else { return };
+            let computed = algorithm.compute(&data);
+            let stored = settings::Endian::Little.word(&stored);
+            let digits = algorithm.len() as usize * 2;
+            if stored == computed {
+                println!("Checksum at ${:06x} is correct: ${:0digits$x}", field, computed);
+            } else {
+                println!("Checksum at ${:06x} is ${:0digits$x}, should be ${:0digits$x}", field, stored, computed);
+                println!("Can not write it: the emulator's debugger interface has no memory writes");
+            }
+        }
+        parser::Cmd::UiStateRegs => show_state(false, tx, rx, session),
+        parser::Cmd::UiStateFull => show_state(true, tx, rx, session),
+        parser::Cmd::UiMarkData(Some(range)) => session.data_ranges.push(range),
+        parser::Cmd::UiMarkData(None) => session.data_ranges.clear(),
+        parser::Cmd::UiDataRanges => {
+            for (start, end) in &session.data_ranges {
+                println!("\t${:06x}-${:06x}", start, end);
+            }
+        }
+        parser::Cmd::UiSkipRanges => {
+            for (start, end) in &session.settings.skip_ranges {
+                println!("\t${:06x}-${:06x}", start, end);
+            }
+        }
+        parser::Cmd::UiLogpoints => {
+            let mut addrs: Vec<&u32> = session.logpoints.keys().collect();
+            addrs.sort();
+            for addr in addrs {
+                let lp = &session.logpoints[addr];
+                match lp.remaining() {
+                    Some(remaining) => println!("\t&{:06x} \"{}\" ({} hits, {} remaining)",
+                                                addr, lp.message, lp.hits.get(), remaining),
+                    None => println!("\t&{:06x} \"{}\" ({} hits)", addr, lp.message, lp.hits.get()),
+                }
+            }
+        }
+        parser::Cmd::UiMemStruct { name, address, count } => {
+            match session.layouts.get(&name) {
+                Some(layout) => {
+                    let Some(len) = layout.size().checked_mul(count).filter(|&len| len <= parser::ADDRESS_SPACE_LEN) else {
+                        println!("{} {}s of {} bytes is more than the 16MB address space", count, name, layout.size());
+                        return;
+                    };
+                    let Some(data) = read_memory(address, len, tx, rx, session) else { return };
+                    print_structs(&name, layout, address, count, &data);
+                }
+                None => println!("Unknown struct: {} (see help struct)", name),
+            }
+        }
+        parser::Cmd::UiSaveState { path, start, len } => {
+            match save_state(&path, start, len, tx, rx, session) {
+                Ok(()) => println!("Saved state to {}", path),
+                Err(e) => println!("Error writing {}: {}", path, e),
+            }
+        }
+        parser::Cmd::UiVariables => {
+            for (name, value) in session.variables.sorted() {
+                println!("${} = ${:x} ({})", name, value, value);
+            }
+        }
+        parser::Cmd::UiVdpDump { path, address, width, height, format } => {
+            let Some(len) = vdp::data_len(width, height, format) else {
+                println!("A {}x{} image is more than the 16MB address space", width, height);
+                return;
+            };
+            let Some(data) = read_memory(address, len, tx, rx, session) else { return };
+            let Some(ppm) = vdp::to_ppm(&data, width, height, format) else { return };
+            match std::fs::write(&path, ppm) {
+                Ok(()) => println!("Wrote {}x{} image to {}", width, height, path),
+                Err(e) => println!("Error writing {}: {}", path, e),
+            }
         }
-        parser::Cmd::UiHelp => print_help(),
-        parser::Cmd::UiExit => state.shutdown(),
         parser::Cmd::End => {}
     }
 }
 
-fn eval_cmd(text: &str, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, state: &EmuState) {
-    match parser::parse_cmd(&mut parser::tokenize(text).into_iter().peekable()) {
-        Ok(cmd) => do_cmd(cmd, tx, rx, state),
+/// Ask the user a yes/no question. When input is piped, there is no one to
+/// ask, and the answer would be taken from the next command, so the answer
+/// is yes.
+fn confirm(question: &str) -> bool {
+    if !std::io::stdin().is_terminal() {
+        println!("{} (y/n) y: input is not a terminal", question);
+        return true;
+    }
+    print!("{} (y/n) ", question);
+    std::io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
+fn delete_triggers(addrs: &[u32], tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    for &addr in addrs {
+        session.logpoints.remove(&addr);
+        session.conditional_breaks.remove(&addr);
+        do_cmd(parser::Cmd::Core(DebugCmd::DeleteTrigger(addr)), tx, rx, session);
+    }
+    println!("Deleted {} breakpoints", addrs.len());
+}
+
+/// Wait up to 'response-timeout' for the response to a command just sent.
+/// If none comes, say so and give None; a late response is handled with
+/// the other unprompted ones.
+fn recv_response(rx: &Receiver<DebugResp>, session: &Session) -> Option<DebugResp> {
+    let timeout = std::time::Duration::from_millis(session.settings.response_timeout as u64);
+    match rx.recv_timeout(timeout) {
+        Ok(resp) => {
+            session.note_cpu_position(&resp);
+            Some(resp)
+        }
+        Err(RecvTimeoutError::Timeout) => {
+            println!("CPU did not respond within {}ms", session.settings.response_timeout);
+            None
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            println!("CPU did not respond: the emulator has stopped");
+            None
+        }
+    }
+}
+
+/// Send `cmd` and wait up to 'response-timeout' for the response that
+/// `extract` accepts. Any unrelated responses that arrive first (eg: from
+/// triggers firing) are handled as usual. None if the CPU doesn't respond,
+/// which has been reported.
+fn request<T>(cmd: DebugCmd, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session,
+              extract: impl Fn(DebugResp) -> Result<T, DebugResp>) -> Option<T> {
+    // a reply that arrived after an earlier request timed out mustn't be
+    // taken as the answer to this one
+    drain_rx(tx, rx, session);
+    // a failed send shows up as the receiver being disconnected
+    let _ = tx.send(cmd);
+    loop {
+        match extract(recv_response(rx, session)?) {
+            Ok(v) => return Some(v),
+            Err(resp) => handle_debug_resp(&resp, session),
+        }
+    }
+}
+
+/// Large memory reads are split into requests of this size
+const MEMORY_CHUNK_LEN: u32 = 0x4000;
+
+/// Read memory from the CPU. Large reads are fetched in chunks, showing
+/// progress when output is a terminal. None if the CPU doesn't respond.
+fn read_memory(start: u32, len: u32, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) -> Option<Vec<u8>> {
+    let use_cache = session.settings.mem_read_cache && session.state.is_in_debugger();
+    if use_cache {
+        if let Some(data) = session.memory_cache.borrow().get(&(start, len)) {
+            return Some(data.clone());
+        }
+    }
+    let show_progress = len > MEMORY_CHUNK_LEN && std::io::stdout().is_terminal();
+    // memory ends within the address space, however much is asked for
+    let mut data = Vec::with_capacity(len.min(parser::ADDRESS_SPACE_LEN) as usize);
+    while (data.len() as u32) < len {
+        let chunk_start = start + data.len() as u32;
+        let chunk_len = (len - data.len() as u32).min(MEMORY_CHUNK_LEN);
+        let chunk = request(DebugCmd::GetMemory { start: chunk_start, len: chunk_len }, tx, rx, session, |resp| match resp {
+            DebugResp::Memory { start, data } if start == chunk_start => Ok(data),
+            resp => Err(resp),
+        })?;
+        let at_end_of_memory = (chunk.len() as u32) < chunk_len;
+        data.extend(chunk);
+        if at_end_of_memory {
+            break;
+        }
+        if show_progress {
+            print!("\rReading memory: {}%", data.len() as u64 * 100 / len as u64);
+            std::io::stdout().flush().unwrap();
+        }
+    }
+    if show_progress {
+        print!("\r{:24}\r", "");
+    }
+    if use_cache {
+        session.memory_cache.borrow_mut().insert((start, len), data.clone());
+    }
+    Some(data)
+}
+
+/// Write a plain text snapshot of the session for bug reports
+fn save_state(path: &str, start: Option<u32>, len: u32, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>,
+              session: &Session) -> std::io::Result<()> {
+    let no_response = || std::io::Error::new(std::io::ErrorKind::TimedOut, "the CPU did not respond");
+    let reg = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+        DebugResp::Registers(reg) => Ok(reg),
+        resp => Err(resp),
+    }).ok_or_else(no_response)?;
+    let triggers = request(DebugCmd::ListTriggers, tx, rx, session, |resp| match resp {
+        DebugResp::Triggers(ts) => Ok(ts),
+        resp => Err(resp),
+    }).ok_or_else(no_response)?;
+    let start = start.unwrap_or(reg.pc);
+    let data = read_memory(start, len, tx, rx, session).ok_or_else(no_response)?;
+
+    let mut out = String::new();
+    out.push_str("[registers]\n");
+    out.push_str(&format!("PC={:06x} {}\n", reg.pc, format_registers(&reg)));
+    out.push_str("\n[breakpoints]\n");
+    for t in &triggers {
+        out.push_str(&format!("&{:06x} {:?}{}\n", t.address, t.actions, if t.once { " (once)" } else { "" }));
+    }
+    out.push_str(&format!("\n[memory ${:06x} ${:x}]\n", start, data.len()));
+    for (row, chunk) in data.chunks(16).enumerate() {
+        let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        out.push_str(&format!("{:06x}: {}\n", start as usize + row * 16, bytes.join(" ")));
+    }
+    out.push_str("\n[history]\n");
+    for line in &session.history {
+        out.push_str(line);
+        out.push('\n');
+    }
+    std::fs::write(path, out)
+}
+
+/// End of the Agon's flash ROM, whose disassembly can be cached as it never
+/// changes
+const ROM_END: u32 = 0x20000;
+
+/// Most ROM disassemblies kept in the cache
+const DISASM_CACHE_LEN: usize = 32;
+
+/// Where the CPU is paused, (pc, adl), asking it if that isn't known.
+/// None if the CPU doesn't respond.
+fn cpu_position(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) -> Option<(u32, bool)> {
+    match session.cpu_position.get() {
+        Some(position) if session.state.is_in_debugger() => Some(position),
+        _ => request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+            DebugResp::Registers(reg) => Ok((reg.pc, reg.adl)),
+            resp => Err(resp),
+        }),
+    }
+}
+
+/// Send a disassembly command, returning (pc, adl, instructions), or None if
+/// the CPU doesn't respond. Listings of ROM are cached, as stepping commands
+/// often list the same code again, including those around PC when where
+/// the CPU is paused is already known from its last state.
+fn disassemble(cmd: DebugCmd, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) -> Option<(u32, bool, Vec<disasm::Line>)> {
+    if let DebugCmd::Disassemble { adl, start, end } = cmd {
+        // disassemble around the first data range in the listing, so that
+        // decoding restarts at its end
+        let first_data = session.data_ranges.iter().copied().filter(|&(s, e)| s < end && e > start).min();
+        if let Some((data_start, data_end)) = first_data {
+            let (pc, cpu_adl) = cpu_position(tx, rx, session)?;
+            let adl = adl.unwrap_or(cpu_adl);
+            let mut lines = vec![];
+            if start < data_start {
+                lines = disassemble(DebugCmd::Disassemble { adl: Some(adl), start, end: data_start }, tx, rx, session)?.2;
+            }
+            let (data_start, data_end) = (data_start.max(start), data_end.min(end));
+            let bytes = read_memory(data_start, data_end - data_start, tx, rx, session)?;
+            lines.extend(disasm::data_lines(data_start, &bytes));
+            if data_end < end {
+                lines.extend(disassemble(DebugCmd::Disassemble { adl: Some(adl), start: data_end, end }, tx, rx, session)?.2);
+            }
+            return Some((pc, adl, lines));
+        }
+    }
+    let cache_key = match cmd {
+        DebugCmd::Disassemble { adl, start, end } if end <= ROM_END => {
+            let (pc, cpu_adl) = cpu_position(tx, rx, session)?;
+            Some((pc, DisasmKey::Range { adl: adl.unwrap_or(cpu_adl), start, end }))
+        }
+        // only worth it when where the CPU is is already known, as asking
+        // would cost as much as the listing
+        DebugCmd::DisassemblePc { adl } => session.cpu_position.get()
+            .filter(|&(pc, _)| pc < ROM_END && session.state.is_in_debugger())
+            .map(|(pc, cpu_adl)| (pc, DisasmKey::AroundPc { adl: adl.unwrap_or(cpu_adl), pc })),
+        _ => None
+    };
+    if let Some((pc, key)) = cache_key {
+        if let Some((_, lines)) = session.disasm_cache.borrow().iter().find(|(k, _)| *k == key) {
+            let (DisasmKey::Range { adl, .. } | DisasmKey::AroundPc { adl, .. }) = key;
+            return Some((pc, adl, lines.clone()));
+        }
+    }
+    let listing_start = match cmd {
+        DebugCmd::Disassemble { start, .. } => Some(start),
+        _ => None,
+    };
+    let (pc, adl, lines) = request(cmd, tx, rx, session, |resp| match resp {
+        DebugResp::Disassembly { pc, adl, disasm }
+            if listing_start.is_none_or(|start| disasm.first().is_none_or(|inst| inst.loc == start)) => {
+            Ok((pc, adl, disasm.into_iter().map(|inst| disasm::Line {
+                loc: inst.loc,
+                asm: inst.asm,
+                bytes: inst.bytes,
+            }).collect::<Vec<disasm::Line>>()))
+        }
+        resp => Err(resp),
+    })?;
+    let in_rom = lines.last().is_none_or(|last| last.loc + last.bytes.len() as u32 <= ROM_END);
+    if let Some((_, key)) = cache_key.filter(|_| in_rom) {
+        let mut cache = session.disasm_cache.borrow_mut();
+        if cache.len() == DISASM_CACHE_LEN {
+            cache.pop_front();
+        }
+        cache.push_back((key, lines.clone()));
+    }
+    Some((pc, adl, lines))
+}
+
+/// Remember where a listing ended, for dis to carry on from, and what it
+/// covered, for detect-smc
+fn listed(lines: &[disasm::Line], session: &Session) {
+    if let (Some(first), Some(last)) = (lines.first(), lines.last()) {
+        let range = (first.loc, last.loc + last.bytes.len() as u32);
+        session.last_disassembly.set(Some(range));
+        if session.settings.detect_smc && !add_code_range(&mut session.code_ranges.borrow_mut(), range) {
+            println!("Not watching ${:06x}-${:06x} for writes: detect-smc already watches {}KB of code \
+                      ('set detect-smc off' forgets it)", range.0, range.1, MAX_CODE_WATCHED / 1024);
+        }
+    }
+}
+
+/// Limit on the bytes of code detect-smc watches, as it reads them all
+/// before and after every step
+const MAX_CODE_WATCHED: u32 = 0x10000;
+
+/// Add `range` to the sorted code ranges, merging it with any it overlaps
+/// or touches. False, leaving them as they were, if that would take them
+/// over MAX_CODE_WATCHED.
+fn add_code_range(ranges: &mut Vec<(u32, u32)>, range: (u32, u32)) -> bool {
+    let (mut start, mut end) = range;
+    let mut merged = Vec::with_capacity(ranges.len() + 1);
+    for &(s, e) in ranges.iter() {
+        if e < start || s > end {
+            merged.push((s, e));
+        } else {
+            start = start.min(s);
+            end = end.max(e);
+        }
+    }
+    merged.push((start, end));
+    merged.sort_unstable();
+    if merged.iter().map(|(s, e)| e - s).sum::<u32>() > MAX_CODE_WATCHED {
+        return false;
+    }
+    *ranges = merged;
+    true
+}
+
+/// With 'detect-smc' on, the code listed so far, to compare after a step.
+/// None if the CPU doesn't respond.
+fn code_snapshot(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) -> Option<Vec<(u32, Vec<u8>)>> {
+    if !session.settings.detect_smc {
+        return Some(vec![]);
+    }
+    let ranges = session.code_ranges.borrow().clone();
+    ranges.into_iter().map(|(start, end)| Some((start, read_memory(start, end - start, tx, rx, session)?))).collect()
+}
+
+/// Report the bytes of code that have changed since `before` was taken,
+/// other than those in data ranges
+fn report_code_writes(before: &[(u32, Vec<u8>)], tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) {
+    let mut writes = BTreeMap::new();
+    for (start, old) in before {
+        let Some(new) = read_memory(*start, old.len() as u32, tx, rx, session) else { return };
+        for (addr, (&o, &n)) in (*start..).zip(old.iter().zip(&new)) {
+            if o != n && !session.data_ranges.iter().any(|&(s, e)| (s..e).contains(&addr)) {
+                writes.insert(addr, (o, n));
+            }
+        }
+    }
+    for (addr, (old, new)) in writes {
+        println!("Write to code at ${:06x}: ${:02x} -> ${:02x}", addr, old, new);
+    }
+}
+
+/// With 'disasm-values' on, follow each instruction that reads or writes a
+/// fixed address with the value there now
+fn annotate_values(lines: &mut [disasm::Line], adl: bool, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) {
+    if !session.settings.disasm_values {
+        return;
+    }
+    // in Z80 mode, the 16-bit addresses are offsets from MB
+    let Some(mbase) = (if adl { Some(0) } else {
+        request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+            DebugResp::Registers(reg) => Ok(reg.mbase as u32),
+            resp => Err(resp),
+        })
+    }) else { return };
+    for inst in lines {
+        let Some((addr, word)) = disasm::memory_operand(&inst.asm) else { continue };
+        let addr = if adl { addr } else { mbase << 16 | (addr & 0xffff) };
+        let len = if !word { 1 } else if adl { 3 } else { 2 };
+        let Some(data) = read_memory(addr, len, tx, rx, session) else { return };
+        let value = settings::Endian::Little.word(&data);
+        inst.asm = format!("{}  ; =${:0width$x}", inst.asm, value, width = len as usize * 2);
+    }
+}
+
+/// List the targets of the jumps and calls in `lines`, recursing up to
+/// `options.follow` levels. Addresses already listed are skipped, which also
+/// stops cycles.
+fn follow_branches(lines: &[disasm::Line], adl: bool, visited: &mut BTreeSet<u32>, options: &disasm::DisasmOptions,
+                   tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) {
+    visited.extend(lines.iter().map(|inst| inst.loc));
+    if options.follow == 0 {
+        return;
+    }
+    let next_level = disasm::DisasmOptions { follow: options.follow - 1, ..*options };
+    for inst in lines {
+        let Some(target) = disasm::branch_target(&inst.asm) else { continue };
+        if visited.contains(&target) {
+            continue;
+        }
+        println!();
+        println!("${:06x}: {} ->", inst.loc, disasm::format_instruction(&inst.asm, &session.settings));
+        // a large listsize mustn't take the listing past the 24-bit address space
+        let end = target.saturating_add(session.settings.listsize).min(0xffffff);
+        let cmd = DebugCmd::Disassemble { adl: Some(adl), start: target, end };
+        let Some((pc, target_adl, mut target_lines)) = disassemble(cmd, tx, rx, session) else { return };
+        if !(options.json || options.bare || options.nolocs) {
+            annotate_values(&mut target_lines, target_adl, tx, rx, session);
+        }
+        disasm::print_listing(pc, target_adl, &target_lines, &session.settings, options);
+        follow_branches(&target_lines, target_adl, visited, &next_level, tx, rx, session);
+    }
+}
+
+/// Copy a listing to the clipboard, or print it if that can't be done
+fn copy_listing(pc: u32, adl: bool, lines: &[disasm::Line], settings: &Settings, options: &disasm::DisasmOptions) {
+    let mut text = Vec::new();
+    let _ = disasm::write_listing(&mut text, pc, adl, lines, settings, options);
+    let text = String::from_utf8_lossy(&text);
+    match clipboard::copy(&text) {
+        Ok(()) => println!("Copied {} lines to the clipboard", text.lines().count()),
+        Err(e) => {
+            print!("{}", text);
+            println!("Could not copy to the clipboard: {}", e);
+        }
+    }
+}
+
+/// The instruction at PC
+struct PcInstruction {
+    pc: u32,
+    len: u32,
+    asm: String,
+    /// Whether the CPU is in ADL mode
+    adl: bool,
+}
+
+impl PcInstruction {
+    /// Whether executing this instruction went somewhere other than the
+    /// instruction following it. A block instruction repeating or halt
+    /// waiting stays where it is without branching, and an instruction
+    /// missing from the listing (len 0) can't be told either way.
+    fn branched_to(&self, next_pc: u32) -> bool {
+        self.len != 0 && next_pc != self.pc + self.len && !self.stayed_in_place(next_pc)
+    }
+
+    /// Whether this instruction is repeating or waiting at the same PC
+    fn stayed_in_place(&self, next_pc: u32) -> bool {
+        next_pc == self.pc && disasm::repeats_in_place(&self.asm)
+    }
+
+    /// Whether the CPU took an interrupt instead of carrying on from this
+    /// instruction: PC moved somewhere new after an instruction that can't
+    /// branch
+    fn took_interrupt(&self, next_pc: u32) -> bool {
+        self.branched_to(next_pc) && !disasm::is_branch(&self.asm)
+    }
+
+    /// How executing this instruction changed the call depth: up for a
+    /// call, rst or interrupt taken, down for a return
+    fn depth_change(&self, next_pc: u32) -> i64 {
+        if !self.branched_to(next_pc) {
+            return 0;
+        }
+        match disasm::mnemonic(&self.asm).as_str() {
+            "call" | "rst" => 1,
+            "ret" | "reti" | "retn" => -1,
+            _ if self.took_interrupt(next_pc) => 1,
+            _ => 0,
+        }
+    }
+}
+
+fn instruction_at_pc(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) -> Option<PcInstruction> {
+    let (pc, adl, lines) = disassemble(DebugCmd::DisassemblePc { adl: None }, tx, rx, session)?;
+    match lines.into_iter().find(|inst| inst.loc == pc) {
+        Some(inst) => Some(PcInstruction { pc, len: inst.bytes.len() as u32, asm: inst.asm, adl }),
+        None => Some(PcInstruction { pc, len: 0, asm: String::new(), adl }),
+    }
+}
+
+/// How a stepping command's search ended
+enum Stepped {
+    /// What it was looking for, after this many steps
+    Found(u32),
+    /// Stopped by Ctrl-C after this many steps
+    Interrupted(u32),
+    /// Gave up after MAX_STEPS
+    GaveUp,
+    /// The CPU stopped responding, which has been reported
+    NoResponse,
+}
+
+/// Single-step until `stop` returns true, given the instruction just
+/// executed and the one now at PC, or until Ctrl-C is pressed
+fn step_until(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session,
+              mut stop: impl FnMut(&PcInstruction, &PcInstruction) -> bool) -> Stepped {
+    let Some(mut inst) = instruction_at_pc(tx, rx, session) else { return Stepped::NoResponse };
+    for steps in 1..=MAX_STEPS {
+        if session.state.is_interrupted() {
+            return Stepped::Interrupted(steps - 1);
+        }
+        if step_quietly(tx, rx, session).is_none() {
+            return Stepped::NoResponse;
+        }
+        let Some(next) = instruction_at_pc(tx, rx, session) else { return Stepped::NoResponse };
+        if stop(&inst, &next) {
+            return Stepped::Found(steps);
+        }
+        inst = next;
+    }
+    Stepped::GaveUp
+}
+
+/// Say that Ctrl-C stopped a stepping command, and show where it got to
+fn stepping_interrupted(steps: u32, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    println!("Interrupted after {} steps", steps);
+    do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session);
+}
+
+/// The address of the top of the stack, in the current ADL mode
+fn stack_top(reg: &Registers) -> u32 {
+    if reg.adl {
+        reg.get24(Reg16::SP)
+    } else {
+        ((reg.mbase as u32) << 16) | reg.get16(Reg16::SP) as u32
+    }
+}
+
+/// Minimum number of stack words shown by examine-stack-frame
+const FRAME_MIN_WORDS: usize = 8;
+
+fn print_stack_frame(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) {
+    let Some((sp, adl)) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+        DebugResp::Registers(reg) => Ok((stack_top(&reg), reg.adl)),
+        resp => Err(resp),
+    }) else { return };
+    let word_len = if adl { 3 } else { 2 };
+    let layout = &session.settings.frame_layout;
+    let num_words = layout.len().max(FRAME_MIN_WORDS);
+    let Some(data) = read_memory(sp, (num_words * word_len) as u32, tx, rx, session) else { return };
+
+    println!("Stack frame at {} ${:06x}:", if adl { "SPL" } else { "SPS" }, sp);
+    for line in stack_frame_lines(sp, adl, &data, layout, &session.symbols) {
+        println!("{}", line);
+    }
+}
+
+/// The lines of examine-stack-frame for the stack words in `data`: each
+/// one's offset from SP, address and value, then its name from the frame
+/// layout and the symbol the value falls in, if any
+fn stack_frame_lines(sp: u32, adl: bool, data: &[u8], layout: &[String], symbols: &symbols::Symbols) -> Vec<String> {
+    let word_len = if adl { 3 } else { 2 };
+    data.chunks_exact(word_len).enumerate().map(|(i, word)| {
+        let value = settings::Endian::Little.word(word);
+        let offset = i * word_len;
+        let line = format!("  SP+{:<3} ${:06x}: ${:0width$x}  {:8}  {}",
+                           offset, (sp + offset as u32) & 0xffffff, value,
+                           layout.get(i).map(|s| s.as_str()).unwrap_or(""),
+                           symbol_offset(value, symbols).unwrap_or_default(),
+                           width = word_len * 2);
+        line.trim_end().to_string()
+    }).collect()
+}
+
+/// `addr` as <symbol> or <symbol>+$<offset>, if it falls between a symbol
+/// and the next one (or is the last symbol itself)
+fn symbol_offset(addr: u32, symbols: &symbols::Symbols) -> Option<String> {
+    let (start, name, next) = symbols.containing(addr)?;
+    match addr - start {
+        0 => Some(name.to_string()),
+        offset if next.is_some() => Some(format!("{}+${:x}", name, offset)),
+        _ => None,
+    }
+}
+
+/// Single-step `count` instructions, stepping over calls into skip ranges
+fn step(count: u32, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    for steps in 0..count {
+        if session.state.is_interrupted() {
+            println!("Interrupted after {} steps", steps);
+            return;
+        }
+        if !session.settings.skip_ranges.is_empty() {
+            let Some(inst) = instruction_at_pc(tx, rx, session) else { return };
+            if disasm::call_target(&inst.asm).is_some_and(|target| session.settings.in_skip_range(target)) {
+                step_over(tx, rx, session);
+                // stepping over rst continues the CPU to a breakpoint
+                if !session.state.is_in_debugger() {
+                    return;
+                }
+                continue;
+            }
+        }
+        let Some(code) = code_snapshot(tx, rx, session) else { return };
+        session.invalidate_memory_cache();
+        let _ = tx.send(DebugCmd::Step);
+        let Some(resp) = recv_response(rx, session) else { return };
+        handle_step_resp(&resp, session);
+        finish_stack(tx, rx, session);
+        report_code_writes(&code, tx, rx, session);
+    }
+}
+
+/// Show the response to step or next, as chosen by 'set step-output'
+fn handle_step_resp(resp: &DebugResp, session: &Session) {
+    session.last_disassembly.set(None);
+    match (session.settings.step_output, resp) {
+        (settings::StepOutput::Compact, DebugResp::State { registers, pc_instruction, .. }) => {
+            println!("{}: {}{}", session.settings.address(registers.pc, registers.adl),
+                     disasm::format_instruction(pc_instruction, &session.settings), branch_note(pc_instruction, registers));
+        }
+        (settings::StepOutput::None, DebugResp::State { .. }) => {}
+        (_, resp) => handle_debug_resp(resp, session),
+    }
+}
+
+/// Step over the instruction at PC. The emulator's StepOver only treats
+/// call as a call, so for rst (MOS system calls) a one-shot breakpoint is
+/// set on the following instruction, where the routine returns to whether
+/// in ADL or Z80 mode, and the CPU continues to it.
+fn step_over(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    session.invalidate_memory_cache();
+    let Some(inst) = instruction_at_pc(tx, rx, session) else { return };
+    if disasm::mnemonic(&inst.asm) != "rst" {
+        let Some(code) = code_snapshot(tx, rx, session) else { return };
+        let _ = tx.send(DebugCmd::StepOver);
+        if let Some(resp) = recv_response(rx, session) {
+            handle_step_resp(&resp, session);
+        }
+        finish_stack(tx, rx, session);
+        report_code_writes(&code, tx, rx, session);
+        return;
+    }
+    do_cmd(parser::Cmd::Core(DebugCmd::AddTrigger(Trigger {
+        address: inst.pc + inst.len,
+        once: true,
+        actions: vec![DebugCmd::Pause, DebugCmd::GetState],
+    })), tx, rx, session);
+    do_cmd(parser::Cmd::Core(DebugCmd::Continue), tx, rx, session);
+}
+
+/// Execute one instruction without showing the resulting CPU state. None
+/// if the CPU doesn't respond.
+fn step_quietly(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) -> Option<()> {
+    let code = code_snapshot(tx, rx, session)?;
+    session.invalidate_memory_cache();
+    request(DebugCmd::Step, tx, rx, session, |resp| match resp {
+        DebugResp::State { .. } => Ok(()),
+        resp => Err(resp),
+    })?;
+    report_code_writes(&code, tx, rx, session);
+    Some(())
+}
+
+/// Limit on how far the stepping commands (watch-expr, step-branch, etc) will go
+const MAX_STEPS: u32 = 1_000_000;
+
+fn watch_expr(expr: &expr::Expr, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    let eval = || expr.eval(&mut CpuEnv { tx, rx, session });
+    let old = match eval() {
+        Ok(v) => v,
+        Err(msg) => { println!("{}", msg); return; }
+    };
+    for steps in 1..=MAX_STEPS {
+        if session.state.is_interrupted() {
+            stepping_interrupted(steps - 1, tx, rx, session);
+            return;
+        }
+        if step_quietly(tx, rx, session).is_none() {
+            return;
+        }
+        match eval() {
+            Ok(new) if new != old => {
+                println!("Expression changed after {} steps: ${:x} ({}) -> ${:x} ({})", steps, old, old, new, new);
+                do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session);
+                return;
+            }
+            Ok(_) => {}
+            Err(msg) => { println!("{}", msg); return; }
+        }
+    }
+    println!("Expression unchanged after {} steps", MAX_STEPS);
+}
+
+fn step_to_branch(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    let found = step_until(tx, rx, session, |inst, next| {
+        if inst.branched_to(next.pc) {
+            println!("Branch taken at ${:06x}: {} -> ${:06x}", inst.pc,
+                     disasm::format_instruction(&inst.asm, &session.settings), next.pc);
+            true
+        } else {
+            false
+        }
+    });
+    match found {
+        Stepped::Found(_) => do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session),
+        Stepped::Interrupted(steps) => stepping_interrupted(steps, tx, rx, session),
+        Stepped::GaveUp => println!("No branch taken after {} steps", MAX_STEPS),
+        Stepped::NoResponse => {}
+    }
+}
+
+fn step_to_write(address: u32, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    let read = || read_memory(address, 1, tx, rx, session).and_then(|data| data.first().copied());
+    let Some(old) = read() else { return };
+    let found = step_until(tx, rx, session, |inst, _| {
+        // if the CPU stops responding, the next step says so
+        let Some(new) = read() else { return false };
+        if new != old {
+            println!("${:06x} written by {} at ${:06x}: ${:02x} -> ${:02x}", address,
+                     disasm::format_instruction(&inst.asm, &session.settings), inst.pc, old, new);
+        }
+        new != old
+    });
+    match found {
+        Stepped::Found(steps) => {
+            println!("Stopped after {} steps", steps);
+            do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session);
+        }
+        Stepped::Interrupted(steps) => stepping_interrupted(steps, tx, rx, session),
+        Stepped::GaveUp => println!("${:06x} unchanged after {} steps", address, MAX_STEPS),
+        Stepped::NoResponse => {}
+    }
+}
+
+fn watch_range(start: u32, end: u32, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    let read = || read_memory(start, end - start, tx, rx, session);
+    let Some(old) = read() else { return };
+    let found = step_until(tx, rx, session, |inst, _| {
+        // if the CPU stops responding, the next step says so
+        let Some(new) = read() else { return false };
+        if new == old {
+            return false;
+        }
+        println!("${:06x}..${:06x} written by {} at ${:06x}:", start, end,
+                 disasm::format_instruction(&inst.asm, &session.settings), inst.pc);
+        print_changed_bytes(start, &old, &new);
+        true
+    });
+    match found {
+        Stepped::Found(steps) => {
+            println!("Stopped after {} steps", steps);
+            do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session);
+        }
+        Stepped::Interrupted(steps) => stepping_interrupted(steps, tx, rx, session),
+        Stepped::GaveUp => println!("${:06x}..${:06x} unchanged after {} steps", start, end, MAX_STEPS),
+        Stepped::NoResponse => {}
+    }
+}
+
+/// List the bytes of a range that differ between two reads of it
+fn print_changed_bytes(start: u32, old: &[u8], new: &[u8]) {
+    let changed: Vec<usize> = (0..old.len()).filter(|&i| old[i] != new[i]).collect();
+    for &i in changed.iter().take(MAX_LISTED_DIFFERENCES) {
+        println!("+{:x} ${:06x}: ${:02x} -> ${:02x}", i, start as usize + i, old[i], new[i]);
+    }
+    if changed.len() > MAX_LISTED_DIFFERENCES {
+        println!("... and {} more", changed.len() - MAX_LISTED_DIFFERENCES);
+    }
+}
+
+fn step_to_instruction(pattern: &str, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    let found = step_until(tx, rx, session, |_, next| {
+        if disasm::matches_instruction(&next.asm, pattern) {
+            println!("Reached {} at ${:06x}", disasm::format_instruction(&next.asm, &session.settings), next.pc);
+            true
+        } else {
+            false
+        }
+    });
+    match found {
+        Stepped::Found(_) => do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session),
+        Stepped::Interrupted(steps) => stepping_interrupted(steps, tx, rx, session),
+        Stepped::GaveUp => println!("No '{}' reached after {} steps", pattern, MAX_STEPS),
+        Stepped::NoResponse => {}
+    }
+}
+
+/// How often wait-idle samples PC
+const IDLE_SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+/// Consecutive samples within IDLE_LOOP_SPAN bytes that count as idle
+const IDLE_SAMPLES: usize = 10;
+const IDLE_LOOP_SPAN: u32 = 0x40;
+
+/// Continue, and pause when PC stays within a small loop, a breakpoint is
+/// hit or `timeout_ms` runs out
+fn wait_for_idle(timeout_ms: u32, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    do_cmd(parser::Cmd::Core(DebugCmd::Continue), tx, rx, session);
+    let deadline = std::time::Instant::now() + std::time::Duration::from_millis(timeout_ms as u64);
+    let mut samples: VecDeque<u32> = VecDeque::new();
+    while !session.state.is_in_debugger() {
+        if std::time::Instant::now() >= deadline {
+            do_cmd(parser::Cmd::Core(DebugCmd::Pause), tx, rx, session);
+            println!("CPU not idle within {}ms", timeout_ms);
+            do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session);
+            return;
+        }
+        if let Ok(resp) = rx.recv_timeout(IDLE_SAMPLE_INTERVAL) {
+            handle_debug_resp(&resp, session);
+            send_follow_ups(tx, session);
+            continue;
+        }
+        let Some(pc) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+            DebugResp::Registers(reg) => Ok(reg.pc),
+            resp => Err(resp),
+        }) else { return };
+        samples.push_back(pc);
+        if samples.len() > IDLE_SAMPLES {
+            samples.pop_front();
+        }
+        let (lo, hi) = (*samples.iter().min().unwrap(), *samples.iter().max().unwrap());
+        if samples.len() == IDLE_SAMPLES && hi - lo < IDLE_LOOP_SPAN {
+            do_cmd(parser::Cmd::Core(DebugCmd::Pause), tx, rx, session);
+            println!("CPU idle, looping in ${:06x}-${:06x}", lo, hi);
+            do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session);
+            return;
+        }
+    }
+}
+
+/// How often mem-wait reads memory while the CPU runs
+const MEM_WAIT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Continue, and pause when the byte at `address` is `value`, or a
+/// breakpoint is hit
+fn wait_for_value(address: u32, value: u8, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    // None if the CPU doesn't respond, Some(None) past the end of memory
+    let read = |session: &Session| read_memory(address, 1, tx, rx, session).map(|data| data.first().copied());
+    let Some(current) = read(session) else { return };
+    let Some(current) = current else {
+        println!("${:06x} is past the end of memory", address);
+        return;
+    };
+    if current == value {
+        println!("${:06x} is already ${:02x}", address, value);
+        return;
+    }
+    do_cmd(parser::Cmd::Core(DebugCmd::Continue), tx, rx, session);
+    while !session.state.is_in_debugger() {
+        if let Ok(resp) = rx.recv_timeout(MEM_WAIT_INTERVAL) {
+            handle_debug_resp(&resp, session);
+            send_follow_ups(tx, session);
+            continue;
+        }
+        match read(session) {
+            Some(Some(current)) if current != value => {}
+            Some(Some(_)) => {
+                do_cmd(parser::Cmd::Core(DebugCmd::Pause), tx, rx, session);
+                let Some(pc) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+                    DebugResp::Registers(reg) => Ok(reg.pc),
+                    resp => Err(resp),
+                }) else { return };
+                println!("${:06x} became ${:02x}; paused at ${:06x}", address, value, pc);
+                do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session);
+                return;
+            }
+            unreadable => {
+                // don't leave the CPU running with nothing watching it
+                do_cmd(parser::Cmd::Core(DebugCmd::Pause), tx, rx, session);
+                println!("Stopped waiting for ${:06x}: {}; CPU paused", address,
+                         if unreadable.is_some() { "it is past the end of memory" } else { "it could not be read" });
+                return;
+            }
+        }
+    }
+}
+
+/// Continue, and wait up to `timeout_ms` for a breakpoint to pause the CPU
+fn wait_for_break(timeout_ms: u32, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    do_cmd(parser::Cmd::Core(DebugCmd::Continue), tx, rx, session);
+    let deadline = std::time::Instant::now() + std::time::Duration::from_millis(timeout_ms as u64);
+    while !session.state.is_in_debugger() {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        match rx.recv_timeout(remaining) {
+            Ok(resp) => {
+                handle_debug_resp(&resp, session);
+                send_follow_ups(tx, session);
+            }
+            Err(_) => {
+                let _ = tx.send(DebugCmd::Pause);
+                let Some(pc) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+                    DebugResp::Registers(reg) => Ok(reg.pc),
+                    resp => Err(resp),
+                }) else { return };
+                session.state.set_in_debugger(true);
+                println!("No breakpoint hit within {}ms; paused at ${:06x}", timeout_ms, pc);
+                return;
+            }
+        }
+    }
+    // Ctrl-C paused the CPU, and the handler asked for its state
+    if session.state.is_interrupted() {
+        return;
+    }
+    let Some(pc) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+        DebugResp::Registers(reg) => Ok(reg.pc),
+        resp => Err(resp),
+    }) else { return };
+    println!("Breakpoint hit at ${:06x}", pc);
+}
+
+fn step_to_adl_change(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    let found = step_until(tx, rx, session, |inst, next| {
+        if inst.adl != next.adl {
+            println!("{} at ${:06x} switched to {} mode; now at ${:06x}",
+                     disasm::format_instruction(&inst.asm, &session.settings), inst.pc,
+                     if next.adl { "ADL (24-bit)" } else { "Z80 (16-bit)" }, next.pc);
+        }
+        inst.adl != next.adl
+    });
+    match found {
+        Stepped::Found(_) => do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session),
+        Stepped::Interrupted(steps) => stepping_interrupted(steps, tx, rx, session),
+        Stepped::GaveUp => println!("ADL mode unchanged after {} steps", MAX_STEPS),
+        Stepped::NoResponse => {}
+    }
+}
+
+/// Run to `start`, unless already there, then single-step to `end`,
+/// counting the instructions executed on the way
+fn time_region(start: u32, end: u32, timeout_ms: u32, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    let Some(pc) = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+        DebugResp::Registers(reg) => Ok(reg.pc),
+        resp => Err(resp),
+    }) else { return };
+    if pc != start {
+        // a breakpoint already at <start> pauses there by itself, and mustn't
+        // be deleted afterwards along with the temporary one
+        let Some(has_trigger) = request(DebugCmd::ListTriggers, tx, rx, session, |resp| match resp {
+            DebugResp::Triggers(ts) => Ok(ts.iter().any(|t| t.address == start)),
+            resp => Err(resp),
+        }) else { return };
+        if !has_trigger {
+            do_cmd(parser::Cmd::Core(DebugCmd::AddTrigger(Trigger {
+                address: start,
+                once: true,
+                actions: vec![DebugCmd::Pause],
+            })), tx, rx, session);
+        }
+        do_cmd(parser::Cmd::Core(DebugCmd::Continue), tx, rx, session);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(timeout_ms as u64);
+        while !session.state.is_in_debugger() {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            match rx.recv_timeout(remaining) {
+                Ok(resp) => {
+                    handle_debug_resp(&resp, session);
+                    send_follow_ups(tx, session);
+                }
+                Err(_) => {
+                    let _ = tx.send(DebugCmd::Pause);
+                    session.state.set_in_debugger(true);
+                    break;
+                }
+            }
+        }
+        let pc = request(DebugCmd::GetRegisters, tx, rx, session, |resp| match resp {
+            DebugResp::Registers(reg) => Ok(reg.pc),
+            resp => Err(resp),
+        });
+        // the one-shot trigger is gone if it was hit, but not otherwise
+        if !has_trigger {
+            do_cmd(parser::Cmd::Core(DebugCmd::DeleteTrigger(start)), tx, rx, session);
+        }
+        let Some(pc) = pc else { return };
+        if pc != start {
+            println!("${:06x} not reached; paused at ${:06x}", start, pc);
+            return;
+        }
+    }
+    match step_until(tx, rx, session, |_, next| next.pc == end) {
+        Stepped::Found(steps) => println!("{} instructions from ${:06x} to ${:06x}", steps, start, end),
+        Stepped::Interrupted(steps) => stepping_interrupted(steps, tx, rx, session),
+        Stepped::GaveUp => println!("${:06x} not reached after {} steps", end, MAX_STEPS),
+        Stepped::NoResponse => {}
+    }
+}
+
+fn step_to_interrupt(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    let found = step_until(tx, rx, session, |inst, next| {
+        if inst.took_interrupt(next.pc) {
+            println!("Interrupt taken at ${:06x} ({}): handler at ${:06x}", inst.pc,
+                     disasm::format_instruction(&inst.asm, &session.settings), next.pc);
+            true
+        } else {
+            false
+        }
+    });
+    match found {
+        Stepped::Found(_) => do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session),
+        Stepped::Interrupted(steps) => stepping_interrupted(steps, tx, rx, session),
+        Stepped::GaveUp => println!("No interrupt taken after {} steps", MAX_STEPS),
+        Stepped::NoResponse => {}
+    }
+}
+
+/// Single-step until the call depth, counted from taken calls and returns,
+/// exceeds `max_depth`
+fn step_to_depth(max_depth: u32, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    let mut depth: i64 = 0;
+    let found = step_until(tx, rx, session, |inst, next| {
+        if inst.branched_to(next.pc) {
+            match disasm::mnemonic(&inst.asm).as_str() {
+                "call" | "rst" => depth += 1,
+                "ret" | "reti" | "retn" => depth -= 1,
+                _ => {}
+            }
+        }
+        depth > max_depth as i64
+    });
+    match found {
+        Stepped::Found(steps) => {
+            println!("Call depth {} exceeds {} after {} steps", depth, max_depth, steps);
+            do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session);
+        }
+        Stepped::Interrupted(steps) => stepping_interrupted(steps, tx, rx, session),
+        Stepped::GaveUp => println!("Call depth stayed within {} for {} steps (now {})", max_depth, MAX_STEPS, depth),
+        Stepped::NoResponse => {}
+    }
+}
+
+/// Step until the current routine returns, or with `stop_on_call` until it
+/// makes a call (or takes an interrupt), whichever comes first
+fn finish(stop_on_call: bool, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    let mut depth: i64 = 0;
+    let found = step_until(tx, rx, session, |inst, next| {
+        depth += inst.depth_change(next.pc);
+        if depth < 0 {
+            println!("Returned to ${:06x}", next.pc);
+        } else if stop_on_call && depth > 0 {
+            println!("Stopped by {} at ${:06x}, entering ${:06x}",
+                     disasm::format_instruction(&inst.asm, &session.settings), inst.pc, next.pc);
+        }
+        depth < 0 || (stop_on_call && depth > 0)
+    });
+    match found {
+        Stepped::Found(_) => do_cmd(parser::Cmd::Core(DebugCmd::GetState), tx, rx, session),
+        Stepped::Interrupted(steps) => stepping_interrupted(steps, tx, rx, session),
+        Stepped::GaveUp => println!("No return after {} steps (call depth now {})", MAX_STEPS, depth),
+        Stepped::NoResponse => {}
+    }
+}
+
+/// Delete the triggers of logpoints that have reached their hit limit. They
+/// print nothing more once used up, but keep costing a message round-trip
+/// per hit until removed.
+fn remove_used_up_logpoints(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    let used_up: Vec<u32> = session.logpoints.iter()
+        .filter(|(_, lp)| lp.remaining() == Some(0))
+        .map(|(&addr, _)| addr)
+        .collect();
+    for addr in used_up {
+        session.logpoints.remove(&addr);
+        do_cmd(parser::Cmd::Core(DebugCmd::DeleteTrigger(addr)), tx, rx, session);
+    }
+}
+
+fn eval_cmd(text: &str, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &mut Session) {
+    if session.settings.input_echo {
+        println!("{}{}", PROMPT, text);
+    }
+    let recalled;
+    let text = match parser::recall(text, &session.history) {
+        Ok(Some(cmd)) => {
+            println!("{}", cmd);
+            recalled = cmd;
+            &recalled
+        }
+        Ok(None) => text,
+        Err(msg) => {
+            println!("{}", msg);
+            return;
+        }
+    };
+    record::log(&session.recording, '$', text);
+    session.state.clear_interrupt();
+    remove_used_up_logpoints(tx, rx, session);
+    let ctx = parser::Context {
+        settings: &session.settings,
+        variables: &session.variables,
+        symbols: &session.symbols,
+        lines: &session.lines,
+        last_disassembly: session.last_disassembly.get(),
+    };
+    match parser::parse_cmd(&mut parser::tokenize(text).into_iter().peekable(), &ctx) {
+        Ok(cmd) => do_cmd(cmd, tx, rx, session),
         Err(msg) => println!("{}", msg)
     }
 }
 
+/// Show the CPU state: the instruction at PC and the registers, then the
+/// words on top of the stack unless `stack` is None
+fn print_state(registers: &Registers, stack: Option<&[u8]>, pc_instruction: &str, session: &Session) {
+    session.last_disassembly.set(None);
+    print!("* {}: {:20} ", session.settings.pc(registers.pc, registers.mbase, registers.adl),
+           disasm::format_instruction(pc_instruction, &session.settings) + &branch_note(pc_instruction, registers));
+    print_registers(registers);
+    let Some(stack) = stack else { return };
+    if registers.adl {
+        print!("{:30} SPL top ${:06x}:", "", registers.get24(Reg16::SP));
+    } else {
+        print!("{:30} SPS top ${:04x}:", "", registers.get16(Reg16::SP));
+    }
+    print_stack_words(stack, 0, if registers.adl { 3 } else { 2 });
+}
+
+/// Print stack words, each labelled with its offset from SP, starting at
+/// `offset`, and end the line
+fn print_stack_words(stack: &[u8], offset: usize, word_len: usize) {
+    let words = stack.chunks_exact(word_len);
+    let rest = words.remainder();
+    for (i, word) in words.enumerate() {
+        print!(" SP+{}=${:0width$x}", offset + i * word_len, settings::Endian::Little.word(word), width = word_len * 2);
+    }
+    for byte in rest {
+        print!(" {:02x}", byte);
+    }
+    println!();
+}
+
+/// Print stack words read after the CPU state, on a line of their own
+/// lined up with the words on the state's stack line
+fn print_more_stack(stack: &[u8], offset: usize, word_len: usize) {
+    print!("{:width$}", "", width = 30 + " SPL top $:".len() + word_len * 2);
+    print_stack_words(stack, offset, word_len);
+}
+
+/// The stack bytes that the CPU state shows with 'set stack-bytes', in
+/// whole words, and how many more must be read from memory to show them
+fn stack_to_show<'a>(stack: &'a [u8], adl: bool, settings: &Settings) -> (&'a [u8], u32) {
+    let Some(bytes) = settings.stack_bytes else { return (stack, 0) };
+    let word_len = if adl { 3 } else { 2 };
+    let len = bytes.div_ceil(word_len) * word_len;
+    match len.checked_sub(stack.len() as u32) {
+        Some(more) => (stack, more),
+        None => (&stack[..len as usize], 0),
+    }
+}
+
+/// Show the stack bytes that stack-bytes asked for beyond those sent with
+/// the CPU state, once they have been read
+fn finish_stack(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) {
+    if session.pending_stack.get().is_none() {
+        return;
+    }
+    send_follow_ups(tx, session);
+    while session.pending_stack.get().is_some() {
+        let Some(resp) = recv_response(rx, session) else {
+            session.pending_stack.set(None);
+            return;
+        };
+        handle_debug_resp(&resp, session);
+        send_follow_ups(tx, session);
+    }
+}
+
+/// For a conditional branch at PC, whether the flags mean it will be taken
+fn branch_note(pc_instruction: &str, registers: &Registers) -> String {
+    let f = registers.get16(Reg16::AF) as u8;
+    let b = (registers.get16(Reg16::BC) >> 8) as u8;
+    match disasm::branch_taken(pc_instruction, f, b) {
+        Some(true) => "  ; will branch".to_string(),
+        Some(false) => "  ; will fall through".to_string(),
+        None => String::new(),
+    }
+}
+
+/// Bytes of stack shown by 'state full'
+const STATE_FULL_STACK_LEN: u32 = 64;
+
+/// Show the CPU state without the stack, or with `full`, followed by a
+/// longer dump of the stack
+fn show_state(full: bool, tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) {
+    let Some((registers, stack, pc_instruction)) = request(DebugCmd::GetState, tx, rx, session, |resp| match resp {
+        DebugResp::State { registers, stack, pc_instruction, .. } => Ok((registers, stack, pc_instruction)),
+        resp => Err(resp),
+    }) else { return };
+    if full {
+        let (stack, more) = stack_to_show(&stack, registers.adl, &session.settings);
+        print_state(&registers, Some(stack), &pc_instruction, session);
+        if more > 0 {
+            let Some(data) = read_memory(stack_top(&registers) + stack.len() as u32, more, tx, rx, session) else { return };
+            print_more_stack(&data, stack.len(), if registers.adl { 3 } else { 2 });
+        }
+        let sp = stack_top(&registers);
+        let Some(data) = read_memory(sp, STATE_FULL_STACK_LEN, tx, rx, session) else { return };
+        print_memory_rows(sp, &data);
+    } else {
+        print_state(&registers, None, &pc_instruction, session);
+    }
+}
+
 fn print_registers(reg: &Registers) {
-    println!("AF:{:04x} BC:{:06x} DE:{:06x} HL:{:06x} SPS:{:04x} SPL:{:06x} IX:{:06x} IY:{:06x} MB {:02x} ADL:{:01x} MADL:{:01x} IFF1:{}",
+    println!("{}", format_registers(reg));
+}
+
+fn print_cpu_info(reg: &Registers, session: &Session) {
+    if reg.adl {
+        println!("Mode:        ADL (24-bit addresses)");
+    } else {
+        println!("Mode:        Z80 (16-bit addresses in the 64KB bank at ${:02x}0000)", reg.mbase);
+    }
+    println!("Mixed mode:  {}", if reg.madl { "on (MADL=1)" } else { "off (MADL=0)" });
+    println!("MB:          ${:02x}", reg.mbase);
+    println!("Interrupts:  {}", if reg.get_iff1() { "enabled (IFF1=1)" } else { "disabled (IFF1=0)" });
+    println!("CPU:         {}", if session.state.is_in_debugger() { "paused" } else { "running" });
+    println!("PC:          ${:06x}", reg.pc);
+}
+
+fn format_registers(reg: &Registers) -> String {
+    format!("AF:{:04x} BC:{:06x} DE:{:06x} HL:{:06x} SPS:{:04x} SPL:{:06x} IX:{:06x} IY:{:06x} MB {:02x} ADL:{:01x} MADL:{:01x} IFF1:{}",
         reg.get16(Reg16::AF),
         reg.get24(Reg16::BC),
         reg.get24(Reg16::DE),
@@ -95,37 +1845,219 @@ fn print_registers(reg: &Registers) {
         reg.adl as i32,
         reg.madl as i32,
         if reg.get_iff1() { '1' } else { '0' },
-    );
+    )
 }
 
-fn handle_debug_resp(resp: &DebugResp, state: &EmuState) {
-    match resp {
-        DebugResp::Memory { start, data } => {
-            let mut pos = *start;
-            for chunk in &mut data.chunks(16) {
-                print!("{:06x}: ", pos);
-                for byte in chunk {
-                    print!("{:02x} ", byte);
-                }
-                print!("| ");
-                for byte in chunk {
-                    let ch = if *byte >= 0x20 && byte.is_ascii() {
-                        char::from_u32(*byte as u32).unwrap_or(' ')
-                    } else {
-                        ' '
-                    };
-                    print!("{}", ch);
-                }
-                println!();
+/// Show memory as bytes, halfwords or words, 16 bytes or 4 words per row
+fn print_units(start: u32, data: &[u8], unit: usize, endian: settings::Endian) {
+    let per_row = if unit == 3 { 4 } else { 16 / unit };
+    for (row, chunk) in data.chunks(per_row * unit).enumerate() {
+        print!("{:06x}:", start as usize + row * per_row * unit);
+        for word in chunk.chunks_exact(unit) {
+            print!(" {:0width$x}", endian.word(word), width = unit * 2);
+        }
+        println!();
+    }
+}
+
+fn print_structs(name: &str, layout: &layout::Layout, address: u32, count: u32, data: &[u8]) {
+    let name_width = layout.fields.iter().map(|f| f.name.len()).max().unwrap_or(0);
+    let size = layout.size() as usize;
+    for i in 0..count as usize {
+        let base = address as usize + i * size;
+        if count == 1 {
+            println!("{} at ${:06x}:", name, base);
+        } else {
+            println!("{}[{}] at ${:06x}:", name, i, base);
+        }
+        for (field, value) in layout.decode(data.get(i * size..).unwrap_or(&[])) {
+            match value {
+                Some(v) => println!("  {:width$} ${:0digits$x} ({})", field.name, v, v,
+                                    width = name_width, digits = field.size as usize * 2),
+                None => println!("  {:width$} beyond end of memory", field.name, width = name_width),
+            }
+        }
+    }
+}
+
+/// Most differences mem-verify lists individually
+const MAX_LISTED_DIFFERENCES: usize = 16;
+
+/// The lines mem-verify reports on comparing memory at `address` with the
+/// file's bytes
+fn verify_memory(address: u32, expected: &[u8], actual: &[u8]) -> Vec<String> {
+    let differences: Vec<usize> = (0..expected.len())
+        .filter(|&i| actual.get(i) != Some(&expected[i]))
+        .collect();
+    if differences.is_empty() {
+        return vec![format!("Verified OK: {} bytes at ${:06x}", expected.len(), address)];
+    }
+    let mut report: Vec<String> = differences.iter().take(MAX_LISTED_DIFFERENCES).map(|&i| match actual.get(i) {
+        Some(byte) => format!("+{:06x} ${:06x}: expected {:02x}, found {:02x}",
+                              i, address as usize + i, expected[i], byte),
+        None => format!("+{:06x} ${:06x}: expected {:02x}, beyond end of memory",
+                        i, address as usize + i, expected[i]),
+    }).collect();
+    if differences.len() > MAX_LISTED_DIFFERENCES {
+        report.push(format!("... and {} more", differences.len() - MAX_LISTED_DIFFERENCES));
+    }
+    report.push(format!("{} of {} bytes differ", differences.len(), expected.len()));
+    report
+}
+
+/// Most matches find-string lists
+const MAX_LISTED_MATCHES: usize = 32;
+
+/// Bytes either side of a match that find-string shows with 'context'
+const FIND_CONTEXT_LEN: usize = 8;
+
+fn print_string_matches(text: &str, start: u32, data: &[u8], nocase: bool, context: bool) {
+    let text = text.as_bytes();
+    let matches: Vec<usize> = data.windows(text.len())
+        .enumerate()
+        .filter(|(_, window)| if nocase { window.eq_ignore_ascii_case(text) } else { *window == text })
+        .map(|(i, _)| i)
+        .collect();
+    for &i in matches.iter().take(MAX_LISTED_MATCHES) {
+        println!("${:06x}", start as usize + i);
+        if context {
+            let from = i.saturating_sub(FIND_CONTEXT_LEN);
+            let to = (i + text.len() + FIND_CONTEXT_LEN).min(data.len());
+            print_memory_rows(start + from as u32, &data[from..to]);
+        }
+    }
+    if matches.len() > MAX_LISTED_MATCHES {
+        println!("... and {} more", matches.len() - MAX_LISTED_MATCHES);
+    }
+    println!("{} matches in ${:06x}..${:06x}", matches.len(), start, start as usize + data.len());
+}
+
+/// Bytes per row of a memory dump: as many as fit the terminal, or 16 when
+/// the width is unknown (eg: output is not a terminal)
+fn memory_row_len() -> usize {
+    match terminal_size::terminal_size() {
+        // "xxxxxx: " + "xx " per byte + "| " + one char per byte
+        Some((terminal_size::Width(width), _)) => {
+            [32, 16, 8, 4].into_iter().find(|n| 10 + 4 * n <= width as usize).unwrap_or(4)
+        }
+        None => 16
+    }
+}
+
+/// Marks the message a logpoint's trigger sends before its registers, so
+/// that they are printed as the logpoint's message instead
+const LOGPOINT_TAG: &str = "\u{1}logpoint ";
+
+/// Bracket the responses to a hit of a conditional breakpoint
+const BREAK_TAG: &str = "\u{1}break ";
+const BREAK_END_TAG: &str = "\u{1}break-end";
+
+/// Whether a hit of a conditional breakpoint, whose CPU state is given,
+/// meets its conditions and should pause the CPU
+fn conditional_break_fires(addr: u32, registers: &Registers, stack: &[u8], session: &Session) -> bool {
+    let Some(b) = session.conditional_breaks.get(&addr) else { return true };
+    if let Some(from) = b.from {
+        let len = if registers.adl { 3 } else { 2 };
+        let Some(ret) = stack.get(..len).map(|bytes| settings::Endian::Little.word(bytes)) else { return false };
+        if from != ret && from != (registers.mbase as u32) << 16 | ret {
+            return false;
+        }
+    }
+    b.hits.set(b.hits.get() + 1);
+    if b.hits.get() < b.after {
+        return false;
+    }
+    if b.after > 1 {
+        println!("Breakpoint at ${:06x} hit {} times", addr, b.hits.get());
+    }
+    if b.show_dis {
+        session.follow_ups.borrow_mut().push(DebugCmd::DisassemblePc { adl: None });
+    }
+    true
+}
+
+/// The CPU has paused: return to the debugger prompt
+fn cpu_paused(session: &Session) {
+    session.state.set_in_debugger(true);
+    // the debugger interface has no instruction or cycle counter, so only
+    // the host's time can be given
+    if let Some(continued_at) = session.continued_at.take() {
+        println!("Ran for {:.3}s (wall-clock)", continued_at.elapsed().as_secs_f64());
+    }
+}
+
+fn print_memory_rows(start: u32, data: &[u8]) {
+    let mut pos = start;
+    let row_len = memory_row_len();
+    for chunk in &mut data.chunks(row_len) {
+        print!("{:06x}: ", pos);
+        for byte in chunk {
+            print!("{:02x} ", byte);
+        }
+        print!("| ");
+        for byte in chunk {
+            let ch = if *byte >= 0x20 && byte.is_ascii() {
+                char::from_u32(*byte as u32).unwrap_or(' ')
+            } else {
+                ' '
+            };
+            print!("{}", ch);
+        }
+        println!();
+
+        pos += row_len as u32;
+    }
+}
 
-                pos += 16;
+fn handle_debug_resp(resp: &DebugResp, session: &Session) {
+    session.note_cpu_position(resp);
+    if session.ignoring_hit.get() {
+        if matches!(resp, DebugResp::Message(s) if s == BREAK_END_TAG) {
+            session.ignoring_hit.set(false);
+            session.invalidate_memory_cache();
+            session.follow_ups.borrow_mut().push(DebugCmd::Continue);
+        }
+        return;
+    }
+    if let DebugResp::State { registers, stack, .. } = resp {
+        if let Some(addr) = session.pending_break.take() {
+            if !conditional_break_fires(addr, registers, stack, session) {
+                session.ignoring_hit.set(true);
+                return;
             }
+            cpu_paused(session);
+            println!("{}", parser::BREAKPOINT_MESSAGE);
+            notify_breakpoint(&session.settings);
         }
+    }
+    match resp {
+        DebugResp::Memory { start, data } => match session.pending_stack.take() {
+            Some((offset, word_len)) => {
+                print_more_stack(data, offset as usize, word_len);
+            }
+            None => print_memory_rows(*start, data),
+        },
+        DebugResp::Message(s) if s == BREAK_END_TAG => {}
+        DebugResp::Message(s) if s == parser::AUTO_CONTINUE_MESSAGE => session.auto_continuing.set(true),
         DebugResp::Message(s) => {
-            println!("{}", s);
+            if let Some(addr) = s.strip_prefix(LOGPOINT_TAG).and_then(|addr| u32::from_str_radix(addr, 16).ok()) {
+                session.pending_logpoint.set(Some(addr));
+            } else if let Some(addr) = s.strip_prefix(BREAK_TAG).and_then(|addr| u32::from_str_radix(addr, 16).ok()) {
+                session.pending_break.set(Some(addr));
+            } else {
+                println!("{}", s);
+                if s == parser::BREAKPOINT_MESSAGE {
+                    notify_breakpoint(&session.settings);
+                }
+            }
         }
-        DebugResp::IsPaused(p) => {
-            state.set_in_debugger(*p);
+        // a conditional breakpoint only pauses once its state has been checked
+        DebugResp::IsPaused(true) if session.pending_break.get().is_some() => {}
+        DebugResp::IsPaused(true) if session.auto_continuing.get() => {}
+        DebugResp::IsPaused(true) => cpu_paused(session),
+        DebugResp::IsPaused(false) => {
+            session.auto_continuing.set(false);
+            session.state.set_in_debugger(false);
         }
         DebugResp::Triggers(bs) => {
             println!("Triggers:");
@@ -138,59 +2070,156 @@ fn handle_debug_resp(resp: &DebugResp, state: &EmuState) {
         }
         DebugResp::Pong => {},
         DebugResp::Disassembly { pc, adl, disasm } => {
-            println!("\t.assume adl={}", if *adl {1} else {0});
-            for inst in disasm {
-                print!("{} {:06x}: {:20} |",
-                       if inst.loc == *pc { "*" } else { " " },
-                       inst.loc,
-                       inst.asm);
-                for byte in &inst.bytes {
-                    print!(" {:02x}", byte);
-                }
-                println!();
-            }
+            let lines: Vec<disasm::Line> = disasm.iter().map(|inst| disasm::Line {
+                loc: inst.loc,
+                asm: inst.asm.to_string(),
+                bytes: inst.bytes.to_vec(),
+            }).collect();
+            disasm::print_listing(*pc, *adl, &lines, &session.settings, &Default::default());
+            listed(&lines, session);
         }
         DebugResp::State { registers, stack, pc_instruction, .. } => {
-            print!("* {:06x}: {:20} ", registers.pc, pc_instruction);
-            print_registers(registers);
-            if registers.adl {
-                print!("{:30} SPL top ${:06x}:", "", registers.get24(Reg16::SP));
-            } else {
-                print!("{:30} SPS top ${:04x}:", "", registers.get16(Reg16::SP));
+            if !session.settings.state_stack {
+                print_state(registers, None, pc_instruction, session);
+                return;
             }
-            for byte in stack {
-                print!(" {:02x}", byte);
+            let (stack, more) = stack_to_show(stack, registers.adl, &session.settings);
+            print_state(registers, Some(stack), pc_instruction, session);
+            if more > 0 {
+                let offset = stack.len() as u32;
+                session.pending_stack.set(Some((offset, if registers.adl { 3 } else { 2 })));
+                session.follow_ups.borrow_mut().push(DebugCmd::GetMemory { start: (stack_top(registers) + offset) & 0xffffff, len: more });
             }
-            println!();
         }
         DebugResp::Registers(registers) => {
-            print!("PC={:06x} ", registers.pc);
-            print_registers(registers);
+            match session.pending_logpoint.take() {
+                // a logpoint that has been deleted or used up prints nothing
+                Some(addr) => {
+                    if let Some(lp) = session.logpoints.get(&addr).filter(|lp| lp.remaining() != Some(0)) {
+                        println!("{}", registers::substitute(&lp.message, registers));
+                        lp.hits.set(lp.hits.get() + 1);
+                        if lp.remaining() == Some(0) {
+                            println!("Logpoint at ${:06x} reached its limit of {} hits and is disabled", addr, lp.hits.get());
+                        }
+                    }
+                }
+                None => {
+                    print!("PC={} ", session.settings.pc(registers.pc, registers.mbase, registers.adl));
+                    print_registers(registers);
+                }
+            }
         }
     }
 }
 
-fn drain_rx(rx: &Receiver<DebugResp>, state: &EmuState) {
-    loop {
-        if let Ok(resp) = rx.try_recv() {
-            handle_debug_resp(&resp, state);
-        } else {
-            break;
+fn drain_rx(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, session: &Session) {
+    while let Ok(resp) = rx.try_recv() {
+        handle_debug_resp(&resp, session);
+        send_follow_ups(tx, session);
+    }
+    finish_stack(tx, rx, session);
+}
+
+/// Ring the bell and run the notify command, as configured
+fn notify_breakpoint(settings: &Settings) {
+    if settings.bell {
+        print!("\x07");
+        std::io::stdout().flush().unwrap();
+    }
+    if let Some(command) = &settings.notify_command {
+        match std::process::Command::new("sh").arg("-c").arg(command).spawn() {
+            // wait for it out of the way, so that it doesn't hold up the
+            // debugger but doesn't linger as a zombie either
+            Ok(mut child) => { std::thread::spawn(move || child.wait()); }
+            Err(e) => println!("Could not run notify-command: {}", e),
         }
     }
 }
 
+/// Send the commands queued while handling a response, eg: to continue the
+/// CPU once all the responses to an ignored breakpoint hit have arrived
+fn send_follow_ups(tx: &Sender<DebugCmd>, session: &Session) {
+    for cmd in session.follow_ups.take() {
+        let _ = tx.send(cmd);
+    }
+}
+
+/// Record a command for save-state, following the history settings
+fn add_to_history(line: &str, session: &mut Session) {
+    if session.settings.history_dedup && session.history.last().is_some_and(|last| last == line) {
+        return;
+    }
+    session.history.push(line.to_string());
+    let excess = session.history.len().saturating_sub(session.settings.history_size as usize);
+    session.history.drain(..excess);
+}
+
+fn apply_history_settings(rl: &mut DefaultEditor, settings: &Settings) {
+    rl.set_max_history_size(settings.history_size as usize).unwrap();
+    rl.set_history_ignore_dups(settings.history_dedup).unwrap();
+}
+
 const PAUSE_AT_START: bool = true;
 
+const PROMPT: &str = ">> ";
+
+/// Where settings are kept between runs
+fn settings_path() -> Option<std::path::PathBuf> {
+    std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".agondbg_settings"))
+}
+
+fn load_settings(settings: &mut Settings) {
+    let Some(path) = settings_path() else { return };
+    let Ok(text) = std::fs::read_to_string(&path) else { return };
+    for msg in settings.load(&text) {
+        println!("{}: {}", path.display(), msg);
+    }
+}
+
+fn save_settings(settings: &Settings) {
+    if let Some(path) = settings_path() {
+        if let Err(e) = settings.save(&path) {
+            println!("Could not save settings to {}: {}", path.display(), e);
+        }
+    }
+}
+
 pub fn start(
     tx: Sender<DebugCmd>,
     rx: Receiver<DebugResp>,
     emulator_shutdown: std::sync::Arc<std::sync::atomic::AtomicBool>
 ) {
-    let state = EmuState {
-        in_debugger: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(PAUSE_AT_START)),
-        emulator_shutdown
+    let mut session = Session {
+        state: EmuState {
+            in_debugger: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(PAUSE_AT_START)),
+            emulator_shutdown,
+            interrupted: Default::default(),
+        },
+        settings: Settings::default(),
+        variables: Variables::default(),
+        symbols: Default::default(),
+        lines: Default::default(),
+        memory_cache: Default::default(),
+        disasm_cache: Default::default(),
+        cpu_position: Cell::new(None),
+        logpoints: HashMap::new(),
+        pending_logpoint: Cell::new(None),
+        last_disassembly: Cell::new(None),
+        data_ranges: Vec::new(),
+        code_ranges: RefCell::new(Vec::new()),
+        conditional_breaks: HashMap::new(),
+        pending_break: Cell::new(None),
+        ignoring_hit: Cell::new(false),
+        auto_continuing: Cell::new(false),
+        follow_ups: RefCell::new(Vec::new()),
+        pending_stack: Cell::new(None),
+        layouts: HashMap::new(),
+        continued_at: Cell::new(None),
+        history: Vec::new(),
+        recording: Default::default(),
     };
+    let (tx, rx) = record::interpose(tx, rx, &session.recording);
+    load_settings(&mut session.settings);
     let tx_from_ctrlc = tx.clone();
 
     // should be able to get this from rl.history(), but couldn't figure out the API...
@@ -204,34 +2233,47 @@ pub fn start(
     }
 
     {
-        let _state = state.clone();
+        let _state = session.state.clone();
         ctrlc::set_handler(move || {
+            _state.interrupt();
+            // the CPU is already paused while a command steps it, and the
+            // command stops itself when it sees the flag
+            if _state.is_in_debugger() {
+                return;
+            }
             _state.set_in_debugger(true);
             println!("Interrupting execution.");
-            tx_from_ctrlc.send(DebugCmd::Pause).unwrap();
-            tx_from_ctrlc.send(DebugCmd::GetState).unwrap();
+            let _ = tx_from_ctrlc.send(DebugCmd::Pause);
+            let _ = tx_from_ctrlc.send(DebugCmd::GetState);
         }).expect("Error setting Ctrl-C handler");
     }
 
     // `()` can be used when no completer is required
     let mut rl = DefaultEditor::new().unwrap();
-    while !state.is_emulator_shutdown() {
-        while state.is_in_debugger() {
-            drain_rx(&rx, &state);
-            let readline = rl.readline(">> ");
+    apply_history_settings(&mut rl, &session.settings);
+    while !session.state.is_emulator_shutdown() {
+        while session.state.is_in_debugger() {
+            drain_rx(&tx, &rx, &session);
+            // a trigger may have continued the CPU since it paused
+            if !session.state.is_in_debugger() {
+                break;
+            }
+            let readline = rl.readline(PROMPT);
             match readline {
                 Ok(line) => {
-                    if line != "" {
+                    if !line.is_empty() {
                         rl.add_history_entry(line.as_str()).unwrap();
-                        eval_cmd(&line, &tx, &rx, &state);
+                        add_to_history(&line, &mut session);
+                        eval_cmd(&line, &tx, &rx, &mut session);
+                        apply_history_settings(&mut rl, &session.settings);
 
-                        if state.is_in_debugger() {
+                        if session.state.is_in_debugger() {
                             last_cmd = Some(line);
                         } else {
                             last_cmd = None;
                         }
                     } else if let Some (ref l) = last_cmd {
-                        eval_cmd(l, &tx, &rx, &state);
+                        eval_cmd(l, &tx, &rx, &mut session);
                         //line = rl.history().last();
                     }
                 },
@@ -239,7 +2281,7 @@ pub fn start(
                     break
                 },
                 Err(ReadlineError::Eof) => {
-                    do_cmd(parser::Cmd::Core(DebugCmd::Continue), &tx, &rx, &state);
+                    do_cmd(parser::Cmd::Core(DebugCmd::Continue), &tx, &rx, &mut session);
                     break
                 },
                 Err(err) => {
@@ -251,7 +2293,88 @@ pub fn start(
 
         // when not reading debugger commands, periodically handle messages
         // from the CPU
-        drain_rx(&rx, &state);
+        drain_rx(&tx, &rx, &session);
         std::thread::sleep(std::time::Duration::from_millis(50));
     }
+    save_settings(&session.settings);
+}
+
+#[cfg(test)]
+fn pc_instruction(pc: u32, len: u32, asm: &str) -> PcInstruction {
+    PcInstruction { pc, len, asm: asm.to_string(), adl: true }
+}
+
+#[test]
+fn test_branched_to() {
+    assert!(!pc_instruction(0x40000, 3, "ld a,(hl)").branched_to(0x40003));
+    assert!(pc_instruction(0x40000, 4, "jp 0x040100").branched_to(0x40100));
+    assert!(!pc_instruction(0x40000, 2, "jr nz,0x040010").branched_to(0x40002));
+    assert!(pc_instruction(0x40000, 1, "ret").branched_to(0x41234));
+    // ldir repeating, or halt waiting, stays put
+    assert!(!pc_instruction(0x40000, 2, "ldir").branched_to(0x40000));
+    assert!(!pc_instruction(0x40000, 2, "LDDR").branched_to(0x40000));
+    assert!(!pc_instruction(0x40000, 1, "halt").branched_to(0x40000));
+    // but an interrupt taken from them is a branch
+    assert!(pc_instruction(0x40000, 2, "ldir").branched_to(0x38));
+    assert!(pc_instruction(0x40000, 1, "halt").branched_to(0x38));
+    // a jump to itself is still a branch
+    assert!(pc_instruction(0x40000, 2, "jr 0x040000").branched_to(0x40000));
+    assert!(!pc_instruction(0x40000, 0, "").branched_to(0x40100));
+}
+
+#[test]
+fn test_depth_change() {
+    assert_eq!(pc_instruction(0x40000, 4, "call 0x041000").depth_change(0x41000), 1);
+    assert_eq!(pc_instruction(0x40000, 4, "call nz,0x041000").depth_change(0x40004), 0);
+    assert_eq!(pc_instruction(0x40000, 1, "rst 0x08").depth_change(0x08), 1);
+    assert_eq!(pc_instruction(0x41000, 1, "ret").depth_change(0x40004), -1);
+    assert_eq!(pc_instruction(0x41000, 1, "ret z").depth_change(0x41001), 0);
+    assert_eq!(pc_instruction(0x41000, 2, "jr 0x041010").depth_change(0x41010), 0);
+    // each repeat of ldir leaves the depth alone; an interrupt raises it
+    assert_eq!(pc_instruction(0x40000, 2, "ldir").depth_change(0x40000), 0);
+    assert_eq!(pc_instruction(0x40000, 2, "ldir").depth_change(0x40002), 0);
+    assert_eq!(pc_instruction(0x40000, 2, "ldir").depth_change(0x38), 1);
+}
+
+#[test]
+fn test_took_interrupt() {
+    assert!(pc_instruction(0x40000, 1, "nop").took_interrupt(0x38));
+    assert!(!pc_instruction(0x40000, 1, "nop").took_interrupt(0x40001));
+    assert!(!pc_instruction(0x40000, 4, "jp 0x040100").took_interrupt(0x40100));
+    // ldir repeating and halt waiting are not interrupts until PC moves on
+    assert!(!pc_instruction(0x40000, 2, "ldir").took_interrupt(0x40000));
+    assert!(!pc_instruction(0x40000, 1, "halt").took_interrupt(0x40000));
+    assert!(pc_instruction(0x40000, 2, "ldir").took_interrupt(0x38));
+    assert!(pc_instruction(0x40000, 1, "halt").took_interrupt(0x38));
+}
+
+#[test]
+fn test_stack_frame_lines() {
+    let symbols = symbols::Symbols::parse("main = $40000\nhelper = $40100\nend = $40200\n");
+    let layout = vec!["ret".to_string(), "arg".to_string()];
+    let data = [0x23, 0x00, 0x04, 0x00, 0x01, 0x04, 0x05, 0x00, 0x00];
+    assert_eq!(stack_frame_lines(0xafffa, true, &data, &layout, &symbols), [
+        "  SP+0   $0afffa: $040023  ret       main+$23",
+        "  SP+3   $0afffd: $040100  arg       helper",
+        "  SP+6   $0b0000: $000005",
+    ]);
+    // words are 16-bit in Z80 mode, and addresses wrap at 24 bits
+    assert_eq!(stack_frame_lines(0xfffffe, false, &[0x34, 0x12, 0x00, 0x02], &[], &symbols), [
+        "  SP+0   $fffffe: $1234",
+        "  SP+2   $000000: $0200",
+    ]);
+}
+
+#[test]
+fn test_verify_memory() {
+    assert_eq!(verify_memory(0x40000, &[1, 2, 3], &[1, 2, 3]), ["Verified OK: 3 bytes at $040000"]);
+    assert_eq!(verify_memory(0x40000, &[1, 2, 3, 4], &[1, 9, 3]), [
+        "+000001 $040001: expected 02, found 09",
+        "+000003 $040003: expected 04, beyond end of memory",
+        "2 of 4 bytes differ",
+    ]);
+    let report = verify_memory(0x40000, &[0; 20], &[1; 20]);
+    assert_eq!(report.len(), MAX_LISTED_DIFFERENCES + 2);
+    assert_eq!(report[MAX_LISTED_DIFFERENCES], "... and 4 more");
+    assert_eq!(report[MAX_LISTED_DIFFERENCES + 1], "20 of 20 bytes differ");
 }
@@ -0,0 +1,65 @@
+//! Source line information: which file and line each address was
+//! assembled from.
+
+use crate::parser::number_from_str;
+
+#[derive(Default)]
+pub struct LineMap {
+    /// (address, file, line), sorted by address
+    entries: Vec<(u32, String, u32)>,
+}
+
+impl LineMap {
+    /// Parse a line map. Each line maps one address, as
+    /// `<file>:<line> <address>`, eg: `main.asm:12 $40000`. Other lines are
+    /// ignored.
+    pub fn parse(text: &str) -> LineMap {
+        let mut entries: Vec<(u32, String, u32)> = text.lines().filter_map(|l| {
+            let (location, address) = l.trim().split_once(char::is_whitespace)?;
+            let (file, line) = location.rsplit_once(':')?;
+            let address = match address.trim().strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number_from_str(address.trim())?,
+            };
+            Some((address, file.to_string(), line.parse().ok()?))
+        }).collect();
+        entries.sort();
+        LineMap { entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The file and line of the code at or before `addr`
+    pub fn line_at(&self, addr: u32) -> Option<(&str, u32)> {
+        let i = self.entries.partition_point(|(a, _, _)| *a <= addr);
+        let (_, file, line) = self.entries.get(i.checked_sub(1)?)?;
+        Some((file, *line))
+    }
+
+    /// The lowest address of code from `file` at `line`. The file may be
+    /// given without its directory.
+    pub fn address_of(&self, file: &str, line: u32) -> Option<u32> {
+        self.entries.iter()
+            .filter(|(_, f, l)| *l == line && (f == file || f.ends_with(&format!("/{}", file))))
+            .map(|(a, _, _)| *a)
+            .min()
+    }
+}
+
+#[test]
+fn test_line_map() {
+    let lines = LineMap::parse("\
+src/main.asm:10 $40000
+src/main.asm:11 $40004
+; comment
+src/util.asm:3 0x40100
+");
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines.line_at(0x40002), Some(("src/main.asm", 10)));
+    assert_eq!(lines.line_at(0x40200), Some(("src/util.asm", 3)));
+    assert_eq!(lines.line_at(0x3ffff), None);
+    assert_eq!(lines.address_of("main.asm", 11), Some(0x40004));
+    assert_eq!(lines.address_of("ain.asm", 11), None);
+}
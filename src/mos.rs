@@ -0,0 +1,69 @@
+//! Names for Agon MOS system calls, to annotate disassembly.
+
+use crate::disasm::{ call_target, decode, immediate };
+
+/// MOS API functions called with rst $08, by their function number in A
+const MOS_API: &[(u32, &str)] = &[
+    (0x00, "mos_getkey"),
+    (0x01, "mos_load"),
+    (0x02, "mos_save"),
+    (0x03, "mos_cd"),
+    (0x04, "mos_dir"),
+    (0x05, "mos_del"),
+    (0x06, "mos_ren"),
+    (0x07, "mos_mkdir"),
+    (0x08, "mos_sysvars"),
+    (0x09, "mos_editline"),
+    (0x0a, "mos_fopen"),
+    (0x0b, "mos_fclose"),
+    (0x0c, "mos_fgetc"),
+    (0x0d, "mos_fputc"),
+    (0x0e, "mos_feof"),
+    (0x0f, "mos_getError"),
+    (0x10, "mos_oscli"),
+    (0x11, "mos_copy"),
+    (0x12, "mos_getrtc"),
+    (0x13, "mos_setrtc"),
+    (0x14, "mos_setintvector"),
+    (0x15, "mos_uopen"),
+    (0x16, "mos_uclose"),
+    (0x17, "mos_ugetc"),
+    (0x18, "mos_uputc"),
+    (0x19, "mos_getfil"),
+    (0x1a, "mos_fread"),
+    (0x1b, "mos_fwrite"),
+    (0x1c, "mos_flseek"),
+];
+
+/// What a MOS call does, for an rst instruction `asm`. `prev_asm` is the
+/// instruction before it, which for the MOS API usually loads the function
+/// number into A.
+pub fn annotation(prev_asm: Option<&str>, asm: &str) -> Option<String> {
+    if decode(asm).mnemonic != "rst" {
+        return None;
+    }
+    match call_target(asm)? {
+        0x08 => {
+            let function = prev_asm.map(decode).and_then(|prev| match &prev.operands[..] {
+                [reg, value] if prev.mnemonic == "ld" && reg.eq_ignore_ascii_case("a") => immediate(value),
+                _ => None
+            });
+            Some(match function.and_then(|f| MOS_API.iter().find(|(n, _)| *n == f)) {
+                Some((_, name)) => name.to_string(),
+                None => "MOS API (function in A)".to_string(),
+            })
+        }
+        0x10 => Some("MOS putch (A)".to_string()),
+        0x18 => Some("MOS write string (HL, length BC, delimiter A)".to_string()),
+        _ => None
+    }
+}
+
+#[test]
+fn test_annotation() {
+    assert_eq!(annotation(Some("ld a,$08"), "rst.lil $08"), Some("mos_sysvars".to_string()));
+    assert_eq!(annotation(Some("ld a,b"), "rst.lil $08"), Some("MOS API (function in A)".to_string()));
+    assert_eq!(annotation(None, "rst $10"), Some("MOS putch (A)".to_string()));
+    assert_eq!(annotation(None, "rst $38"), None);
+    assert_eq!(annotation(None, "call $08"), None);
+}
@@ -1,27 +1,162 @@
 use agon_cpu_emulator::debugger::{ DebugCmd, Trigger };
+use crate::checksum::Algorithm;
+use crate::disasm::DisasmOptions;
+use crate::expr::{ Expr, Variables };
+use crate::layout::Layout;
+use crate::lines::LineMap;
+use crate::registers::Adjust;
+use crate::settings::Settings;
+use crate::symbols::Symbols;
+use crate::vdp::{ self, PixelFormat };
 
 #[derive(Debug)]
 pub enum Cmd {
     Core(DebugCmd),
-    UiHelp,
+    UiHelp(Option<String>),
     UiExit,
+    UiClear,
+    UiSet(String, String),
+    UiMarkData(Option<(u32, u32)>),
+    UiDataRanges,
+    UiStateRegs,
+    UiStateFull,
+    UiFixChecksum { start: u32, len: u32, field: u32, algorithm: Algorithm },
+    UiMemoryVdu { start: u32, len: u32 },
+    UiShow(Option<String>),
+    UiWatchExpr(Expr),
+    UiWatchRange { start: u32, end: u32 },
+    UiStep(u32),
+    UiStepBranch,
+    UiStepToWrite(u32),
+    UiBreakAdlChange,
+    UiBreakDepth(u32),
+    UiBreakOp(String),
+    UiBreakpoints(Vec<Trigger>),
+    UiBreakAtPc { offset: i64 },
+    UiToggle(u32),
+    UiTime { start: u32, end: u32, timeout_ms: u32 },
+    UiConditionalBreak { addresses: Vec<u32>, after: u32, from: Option<u32>, show_dis: bool },
+    UiWaitBreak(u32),
+    UiWaitIdle(u32),
+    UiFinish { stop_on_call: bool },
+    UiContinueToInterrupt,
+    UiSetRegister(String, u32),
+    UiAdjustRegister(String, Adjust, u32),
+    UiLoadSymbols(String),
+    UiLoadLines(String),
+    UiRecord(Option<String>),
+    UiReplay(String),
+    UiInfoLine(u32),
+    UiLogpoint { address: u32, message: String, limit: Option<u32> },
+    UiLogpoints,
+    UiAllRegisters,
+    UiRegistersVerbose,
+    UiCpuInfo,
+    UiVdpInfo(Option<u32>),
+    UiSkipRanges,
+    UiStructDefine(String, Layout),
+    UiMemStruct { name: String, address: u32, count: u32 },
+    UiSaveState { path: String, start: Option<u32>, len: u32 },
+    UiDisassembleFunction,
+    UiStackFrame,
+    UiAssign(String, Expr),
+    UiPrint(Expr),
+    UiVariables,
+    UiDelete(Vec<u32>),
+    UiDeleteAll,
+    UiDisassemble(DebugCmd, DisasmOptions),
+    UiExamine { start: u32, count: u32, unit: u32 },
+    UiMemVerify { path: String, address: u32 },
+    UiFindString { text: String, start: u32, end: u32, nocase: bool, context: bool },
+    UiMemWait { address: u32, value: u8 },
+    UiVdpDump { path: String, address: u32, width: u32, height: u32, format: PixelFormat },
     End
 }
 
+/// Longest disassembly listed without asking first
+const MAX_DISASSEMBLY_SPAN: u32 = 0x1000;
+
+/// Bytes in the eZ80's 24-bit address space: the most memory any one
+/// command can read
+pub const ADDRESS_SPACE_LEN: u32 = 0x1000000;
+
+impl Cmd {
+    /// The question to ask before running a command that would flood the
+    /// terminal, when 'confirm' is on. (delete with no addresses asks once
+    /// it knows how many breakpoints there are.)
+    pub fn confirmation(&self) -> Option<String> {
+        match self {
+            Cmd::Core(DebugCmd::Disassemble { start, end, .. }) |
+            Cmd::UiDisassemble(DebugCmd::Disassemble { start, end, .. }, _)
+                if end.saturating_sub(*start) > MAX_DISASSEMBLY_SPAN => {
+                Some(format!("Disassemble {} bytes (${:06x}-${:06x})?", end - start, start, end))
+            }
+            _ => None
+        }
+    }
+}
+
+/// Why a command could not be parsed. Displays as the message shown to
+/// the user.
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// No command has this name, or a name starting with it
+    UnknownCommand(String),
+    /// More than one command's name starts with this
+    AmbiguousCommand { name: String, matches: Vec<String> },
+    /// A token left over after a complete command
+    UnexpectedToken(String),
+    /// The arguments don't fit the command: holds its usage
+    Usage(String),
+    /// Any other problem, such as an invalid range or expression
+    Invalid(String),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::UnknownCommand(name) => write!(f, "Unknown command: {}", name),
+            ParseError::AmbiguousCommand { name, matches } => {
+                write!(f, "Ambiguous command '{}': {}", name, matches.join(", "))
+            }
+            ParseError::UnexpectedToken(t) => write!(f, "Expected end of command but found '{}'", t),
+            ParseError::Usage(usage) | ParseError::Invalid(usage) => write!(f, "{}", usage),
+        }
+    }
+}
+
+impl From<&str> for ParseError {
+    fn from(usage: &str) -> Self {
+        ParseError::Usage(usage.to_string())
+    }
+}
+
+impl From<String> for ParseError {
+    fn from(msg: String) -> Self {
+        ParseError::Invalid(msg)
+    }
+}
+
 type Tokens<'a> = std::iter::Peekable<std::vec::IntoIter<&'a str>>;
 
+/// Debugger state that affects how commands are parsed
+pub struct Context<'a> {
+    pub settings: &'a Settings,
+    pub variables: &'a Variables,
+    pub symbols: &'a Symbols,
+    pub lines: &'a LineMap,
+    /// Addresses (start, end exclusive) of the last disassembly listing,
+    /// until the CPU state is next shown
+    pub last_disassembly: Option<(u32, u32)>,
+}
+
 // trigger $40000 "hey" pause state
 pub fn tokenize(line: &str) -> Vec<&str> {
     //line.split_whitespace().collect::<Vec<&str>>().into_iter()
     let mut l = line;
     let mut v: Vec<&str> = vec![];
     
-    loop {
-        let tok_start = match l.chars().position(|ch| !ch.is_whitespace()) {
-            Some(p) => p,
-            None => break
-        };
-
+    while let Some(tok_start) = l.chars().position(|ch| !ch.is_whitespace()) {
         let tok_end = {
             match l.chars().nth(tok_start).unwrap() {
                 ':' => tok_start + 1,
@@ -45,174 +180,1454 @@ pub fn tokenize(line: &str) -> Vec<&str> {
     v
 }
 
-fn expect_end_of_cmd(tokens: &mut Tokens) -> Result<(), String> {
+/// Collect the tokens up to the end of the command, eg: an expression
+fn rest_of_cmd(tokens: &mut Tokens) -> String {
+    let mut words = vec![];
+    while let Some(&t) = tokens.peek() {
+        if t == ":" { break }
+        words.push(t);
+        tokens.next();
+    }
+    words.join(" ")
+}
+
+fn expect_end_of_cmd(tokens: &mut Tokens) -> Result<(), ParseError> {
     match tokens.peek() {
         Some(&t) => {
             if t == ":" {
                 Ok(())
             } else {
-                Err(format!("Expected end of command but found '{}'", t))
+                Err(ParseError::UnexpectedToken(t.to_string()))
             }
         }
         None => Ok(())
     }
 }
 
-pub fn parse_cmd(tokens: &mut Tokens) -> Result<Cmd, String> {
-    if let Some(tok) = tokens.next() {
-        match tok {
-            "triggers" => {
-                expect_end_of_cmd(tokens)?;
-                Ok(Cmd::Core(DebugCmd::ListTriggers))
-            }
-            "trigger" => {
-                if let Some(addr) = parse_number(tokens) {
-                    let mut actions = vec![];
-                    loop {
-                        match parse_cmd(tokens)? {
-                            Cmd::Core(a @ DebugCmd::AddTrigger(_)) => {
-                                return Err(format!("Invalid action to trigger: {:?}", a));
-                            }
-                            Cmd::Core(a) => actions.push(a),
-                            Cmd::End => break,
-                            a => {
-                                return Err(format!("Invalid action to trigger: {:?}", a));
-                            }
-                        }
-                        if let Some(&t) = tokens.peek() {
-                            if t != ":" { break }
-                            else { tokens.next(); }
-                        }
-                    }
-                    expect_end_of_cmd(tokens)?;
-                    let trigger = DebugCmd::AddTrigger(Trigger {
-                        address: addr,
-                        once: false,
-                        actions
-                    });
-                    Ok(Cmd::Core(trigger))
-                } else {
-                    Err(format!("trigger expects an address argument"))
-                }
-            }
-            "pause" => {
-                expect_end_of_cmd(tokens)?;
-                Ok(Cmd::Core(DebugCmd::Pause))
-            }
-            "help" => {
-                expect_end_of_cmd(tokens)?;
-                Ok(Cmd::UiHelp)
-            }
-            "info" => {
-                match tokens.next() {
-                    Some("breakpoints") => {
-                        expect_end_of_cmd(tokens)?;
-                        Ok(Cmd::Core(DebugCmd::ListTriggers))
-                    }
-                    _ => Err("Unknown info type".to_string())
-                }
-            }
-            "delete" => {
-                if let Some(addr) = parse_number(tokens) {
-                    expect_end_of_cmd(tokens)?;
-                    Ok(Cmd::Core(DebugCmd::DeleteTrigger(addr)))
-                } else {
-                    Err(format!("delete expects an address argument"))
-                }
-            }
-            "br" | "break" => {
-                if let Some(addr) = parse_number(tokens) {
-                    expect_end_of_cmd(tokens)?;
-                    Ok(Cmd::Core(DebugCmd::AddTrigger(Trigger {
-                        address: addr,
-                        once: false,
-                        actions: vec![
-                            DebugCmd::Pause,
-                            DebugCmd::Message("CPU paused at breakpoint".to_string()),
-                            DebugCmd::GetState,
-                        ]
-                    })))
-                } else {
-                    Err(format!("break <address>"))
-                }
-            }
-            "exit" => {
-                expect_end_of_cmd(tokens)?;
-                Ok(Cmd::UiExit)
-            }
-            "n" | "next" => {
-                expect_end_of_cmd(tokens)?;
-                Ok(Cmd::Core(DebugCmd::StepOver))
-            }
-            "s" | "step" => {
-                expect_end_of_cmd(tokens)?;
-                Ok(Cmd::Core(DebugCmd::Step))
-            }
-            "trace" => {
-                if parse_exact(tokens, "on") {
-                    expect_end_of_cmd(tokens)?;
-                    Ok(Cmd::Core(DebugCmd::SetTrace(true)))
-                }
-                else if parse_exact(tokens, "off") {
-                    expect_end_of_cmd(tokens)?;
-                    Ok(Cmd::Core(DebugCmd::SetTrace(false)))
-                }
-                else {
-                    Err(format!("expected 'on' or 'off'"))
-                }
-            }
-            "registers" => {
-                expect_end_of_cmd(tokens)?;
-                Ok(Cmd::Core(DebugCmd::GetRegisters))
-            }
-            "mem" | "memory" => {
-                let start_ = parse_number(tokens);
-                if let Some(start) = start_ {
-                    let len = parse_number(tokens).unwrap_or(16);
-                    expect_end_of_cmd(tokens)?;
-                    Ok(Cmd::Core(DebugCmd::GetMemory { start, len }))
-                } else {
-                    Err(format!("mem <start> [len]"))
+/// A debugger command. The first name is the canonical one: any unambiguous
+/// prefix of it also selects the command. The remaining names are aliases,
+/// which only match exactly.
+struct Command {
+    names: &'static [&'static str],
+    help: &'static str,
+    parse: fn(&mut Tokens, &Context) -> Result<Cmd, ParseError>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command { names: &["break", "br"], help: "\
+break <address> [address ...] [after <n>] [from <caller>] [show dis]
+break [pc][+|-<offset>]
+
+Set a breakpoint at each <address>. With no address, or pc, set one at the
+current PC, or <offset> bytes after or before it, eg: break +8. When the
+CPU reaches one, execution is paused, a message is printed and the CPU
+state is shown. With 'after <n>', the breakpoint first pauses on its <n>th
+hit, and on every hit after that: the first <n>-1 hits are counted and the
+CPU carried on. With 'from <caller>', it only pauses when the return
+address on top of the stack is <caller>, ie: on entry to a routine called
+from there; other hits are not counted. With 'show dis', a disassembly of
+the instructions around PC is shown as well.
+Addresses may be decimal, hex written as $40000, &40000 or 40000h, the
+names of loaded symbols (see help load-symbols), or source locations as
+<file>:<line> (see help load-lines).
+
+Example: break $40000 show dis
+         break pc+3
+         break $40000 after 50
+         break $40000 from $41234
+         break main init loop
+         break main.asm:42",
+        parse: parse_break },
+    Command { names: &["break-adl-change"], help: "\
+break-adl-change
+
+Single-step until the CPU switches between ADL (24-bit) and Z80 (16-bit)
+mode, eg: by a .lil or .sis call, jump or return, or by an interrupt. Then
+show the instruction that switched, its address, the new mode and the CPU
+state.",
+        parse: parse_break_adl_change },
+    Command { names: &["break-depth"], help: "\
+break-depth <n>
+
+Single-step until the call depth exceeds <n> frames, to catch runaway
+recursion. Depth is counted from where the command starts: each call or
+rst taken adds a frame and each return removes one. Shows the depth and
+CPU state when it stops.
+
+Example: break-depth 10",
+        parse: parse_break_depth },
+    Command { names: &["break-op"], help: "\
+break-op <instruction>
+
+Single-step until the CPU is about to execute a matching instruction, then
+show its PC and the CPU state. A mnemonic alone matches any operands;
+given operands must match too, with * matching any one operand.
+
+Example: break-op halt
+         break-op out (c),*",
+        parse: parse_break_op },
+    Command { names: &["clear", "cls"], help: "\
+clear
+
+Clear the terminal screen.",
+        parse: parse_clear },
+    Command { names: &["continue", "c", "cont"], help: "\
+continue
+
+Resume (un-pause) the Agon CPU. Press <CTRL-C> to pause it again. When it
+next pauses, eg: at a breakpoint, the wall-clock time it ran for is shown.
+The emulator doesn't report how many instructions it ran, or the emulated
+time.",
+        parse: parse_continue },
+    Command { names: &["continue-to-interrupt"], help: "\
+continue-to-interrupt
+
+Single-step until the CPU services an interrupt, then show where the
+interrupt was taken, the handler's address and the CPU state. An interrupt
+is recognised by PC jumping elsewhere after an instruction that is not a
+jump, call or return (including when leaving halt).",
+        parse: parse_continue_to_interrupt },
+    Command { names: &["delete"], help: "\
+delete [address ...]
+
+Delete the breakpoints or triggers at the given addresses. With no
+addresses, delete all of them, after asking for confirmation (see
+confirm in help set).
+
+Example: delete $40000 $40010",
+        parse: parse_delete },
+    Command { names: &["dis-func"], help: "\
+dis-func
+
+Disassemble the whole function containing PC: from the nearest symbol at
+or before PC up to the next symbol. Needs symbols (see help load-symbols).",
+        parse: parse_disassemble_function },
+    Command { names: &["dis16"], help: "\
+dis16 [start] [end] [options]
+
+Disassemble in ADL=0 (Z80) mode, regardless of the current CPU mode.
+With no arguments, disassemble from PC.",
+        parse: parse_dis16 },
+    Command { names: &["dis24"], help: "\
+dis24 [start] [end] [options]
+
+Disassemble in ADL=1 (24-bit) mode, regardless of the current CPU mode.
+With no arguments, disassemble from PC.",
+        parse: parse_dis24 },
+    Command { names: &["disassemble", "dis"], help: "\
+disassemble [start] [end] [options]
+disassemble <start>..<end> [options]
+disassemble - [options]
+
+Disassemble in the current ADL mode. With no arguments, carry on from the
+end of the last listing, or disassemble from PC if there has been none
+since the CPU state was last shown. With -, disassemble the 'listsize'
+bytes before the last listing instead. If <end> is omitted, 'listsize'
+bytes are disassembled (see help set), unless <start> is the name of a
+loaded symbol: then the listing ends at the next symbol, so that dis main
+lists all of main. Either end may be a symbol, eg: dis main parse.
+Ranges over 4KB are only disassembled after asking first, unless the
+'confirm' setting is off.
+Options (also accepted by dis16 and dis24):
+
+skip-padding    Collapse runs of filler bytes, such as nop or rst $38
+                padding, into a single line
+reassemblable   Emit assembler source: an .org directive, and labels for
+                jump and call targets within the listing
+follow <depth>  After the listing, also list the targets of its jumps and
+                calls with known addresses ('listsize' bytes each), and
+                theirs in turn, up to <depth> levels. Each address is
+                listed only once.
+mos             Name the Agon MOS calls made with rst, eg: the MOS API
+                function loaded into A before an rst $08
+mixed           Show each instruction's bytes in hex and ASCII, as in a
+                memory dump, to help spot data mixed in with code
+json            One JSON object per line for each instruction, with its
+                address, bytes, mnemonic, operands, kind (jump, call,
+                return or other) and branch target, for use by frontends
+bare            Just each instruction's address and the instruction, with
+                whitespace normalized, so that two listings diff cleanly
+nolocs          As bare, but without the addresses, so that a diff shows
+                inserted or removed instructions rather than every address
+blocks          Split the listing into basic blocks, each headed by its
+                address and the addresses in the listing that jump or call
+                to it. Blocks end after a jump or return, and start at the
+                targets of jumps and calls within the listing.
+clip            Copy the listing to the system clipboard instead of
+                printing it (followed targets are still printed). Uses
+                pbcopy, wl-copy, xclip, xsel or clip.exe, whichever is
+                installed; if none works, the listing is printed instead.
+
+Example: dis $40000 $40040",
+        parse: parse_disassemble },
+    Command { names: &["examine-stack-frame", "frame"], help: "\
+examine-stack-frame
+
+Interpret the top of the stack as a call frame. Each stack word (3 bytes
+in ADL mode, 2 in Z80 mode) is shown with its offset from SP, its name
+from the 'frame-layout' setting, and the loaded symbol its value falls
+in, such as the routine a return address is in. For a routine that
+starts with 'push ix' the layout would be: set frame-layout ix,ret,arg1,arg2",
+        parse: parse_stack_frame },
+    Command { names: &["exit"], help: "\
+exit
+
+Quit from Agon Light Emulator.",
+        parse: parse_exit },
+    Command { names: &["find-string"], help: "\
+find-string \"<text>\" [<start> <end>] [nocase] [context]
+find-string \"<text>\" <start>..<end> [options]
+
+Search memory for ASCII text, eg: a message a program prints, and list
+the addresses where it is found. Without a range, all of RAM ($40000 to
+$c0000) is searched. Options:
+
+nocase          Match upper and lower case letters alike
+context         Show the bytes around each match, as in a memory dump
+
+Example: find-string \"Hello, world\" nocase",
+        parse: parse_find_string },
+    Command { names: &["finish"], help: "\
+finish [calls]
+
+Single-step until the current routine returns, then show where it returned
+to and the CPU state. Calls and interrupts taken on the way are stepped
+through, and must return first. With 'calls', stop instead at the first
+call, rst or interrupt taken from the routine, to catch one that never
+returns. Reports whether the routine returned or a call stopped it.
+
+Example: finish calls",
+        parse: parse_finish },
+    Command { names: &["fix-checksum"], help: "\
+fix-checksum <start> <len> <address> [sum8|crc32]
+
+Compute a checksum of <len> bytes from <start>, and compare it with the
+checksum field at <address>, stored little-endian: 1 byte for sum8 (the
+bytes added modulo 256, the default) or 4 for crc32 (as used by zip). When
+the field is wrong, the value it should hold is shown. Note that this
+emulator's debugger interface can not yet write memory, so the field can
+not be corrected in place.
+
+Example: fix-checksum $40000 $1000 $41000 crc32",
+        parse: parse_fix_checksum },
+    Command { names: &["help"], help: "\
+help [command]
+
+List all commands, or show detailed help for one command.
+
+Example: help break",
+        parse: parse_help },
+    Command { names: &["info"], help: "\
+info <topic>
+
+Show information about one of these topics:
+
+all-registers   Every register as a labelled block, with 8-bit halves and
+                decoded flags. The emulator does not expose I, R or the
+                alternate register set.
+breakpoints     Breakpoints and other triggers
+data            The ranges disassembly shows as data (see help mark-data)
+cpu             A summary of the CPU's mode and state. The emulator does
+                not report the interrupt mode or clock speed.
+line <address>  The source line <address> was assembled from (see help
+                load-lines)
+logpoints       Logpoints, with their remaining hits
+skip            The ranges that step steps over calls into (see help set)
+variables       Debugger variables
+vdp [sysvars]   The video mode, resolution and colours, as recorded in the
+                MOS system variables at [sysvars] (the address mos_sysvars
+                returns in IX), or at the symbol _sysvars if loaded",
+        parse: parse_info },
+    Command { names: &["memory", "mem"], help: "\
+memory <start> [len] [vdu]
+memory <start>..<end> [vdu]
+
+Dump <len> bytes of memory from <start>, or from <start> up to but not
+including <end>, as hex and ASCII. If <len> is omitted, 'dumpsize' bytes
+are shown (see help set). With 'vdu', the bytes are read as a stream of
+VDU commands, as sent to the VDP, and each row is followed by the names of
+the commands starting in it, eg: MODE 3.
+
+Example: mem $40000 64
+         mem $40000..$40100
+         mem $40000 64 vdu",
+        parse: parse_memory },
+    Command { names: &["load-lines"], help: "\
+load-lines <file>
+
+Load source line information, replacing any loaded before, for info line
+and break <file>:<line>. Each line of the file maps one address, as
+<file>:<line> <address>, eg: main.asm:12 $40000; other lines are ignored.
+
+Example: load-lines hello.lines",
+        parse: parse_load_lines },
+    Command { names: &["load-symbols"], help: "\
+load-symbols <file>
+
+Load symbols from an assembler symbol or map file, replacing any loaded
+before. Each line defines one symbol, as 'name = $40000', 'name: equ
+$40000', 'name $40000' or '$40000 name'; other lines are ignored.
+
+Example: load-symbols hello.sym",
+        parse: parse_load_symbols },
+    Command { names: &["logpoint"], help: "\
+logpoint <address> \"<message>\" [limit <n>]
+
+Print <message> each time the CPU reaches <address>, without pausing.
+%NAME in the message is replaced by that register's value in hex, eg: %A,
+%HL, %SP or %PC; %% is a literal %. With a limit, the logpoint disables
+itself after <n> hits. Remove it with delete <address>, and list them with
+info logpoints.
+
+Example: logpoint $40000 \"A=%A HL=%HL\" limit 100",
+        parse: parse_logpoint },
+    Command { names: &["mark-data"], help: "\
+mark-data <start> <end>
+mark-data <start>..<end>
+mark-data off
+
+Mark the addresses from <start> up to but not including <end> as data, such
+as a string or a table. Disassembly listings then show those bytes as db
+lines, instead of decoding them as instructions, and start decoding again
+at <end>. Mark more ranges by repeating the command, list them with info
+data, and remove them all with 'off'.
+
+Example: mark-data $40100 $40140",
+        parse: parse_mark_data },
+    Command { names: &["mem-struct"], help: "\
+mem-struct <name> <address> [count]
+
+Decode memory at <address> into the fields of a struct defined with
+'struct define', or an array of <count> of them.
+
+Example: mem-struct Sprite $40000 4",
+        parse: parse_mem_struct },
+    Command { names: &["mem-verify"], help: "\
+mem-verify <file> <address>
+
+Compare memory from <address> with the contents of <file>, eg: to check
+that a program image loaded correctly. Differences are listed with their
+offset into the file and the expected and actual bytes.
+
+Example: mem-verify hello.bin $40000",
+        parse: parse_mem_verify },
+    Command { names: &["mem-wait"], help: "\
+mem-wait <address> <value>
+
+Resume the CPU, and pause it when the byte at <address> becomes <value>,
+eg: when an interrupt handler sets a flag. The byte is read every 10ms
+while the CPU runs, so a value that only lasts a moment may be missed.
+Shows the PC when the value was seen, and the CPU state. Also stops at
+breakpoints, and does not resume the CPU if the byte already has <value>.
+
+Example: mem-wait $40000 $a5",
+        parse: parse_mem_wait },
+    Command { names: &["next", "n"], help: "\
+next
+
+Execute one instruction, stepping over function calls. rst is treated as
+a call too, so MOS system calls are stepped over rather than into.",
+        parse: parse_next },
+    Command { names: &["pause"], help: "\
+pause
+
+Pause execution and enter the debugger. Mostly useful as a trigger
+action.",
+        parse: parse_pause },
+    Command { names: &["print", "p"], help: "\
+print <expression>
+
+Evaluate an expression and print its value in hex and decimal. See
+help watch-expr for the expression syntax.
+
+Debugger variables are set with $name = <expression>, and can then be
+used in expressions and as command arguments. $ on its own is the last
+value printed or assigned. Variable names must not be valid hex numbers.
+
+Example: $ptr = [$40000] + [$40001] * 256
+         print $ptr + 2
+         mem $ptr",
+        parse: parse_print },
+    Command { names: &["record"], help: "\
+record <file>
+record off
+
+Record the session to <file> until record off: each command typed, each
+request the debugger sends to the CPU and each response from it, one per
+line, with the seconds since the recording started. Commands are marked $,
+requests > and responses <. An existing file is overwritten.
+
+Example: record crash.log",
+        parse: parse_record },
+    Command { names: &["reg"], help: "\
+reg <register> <value>
+reg <register> <op> <value>
+
+Check a value for writing to a register: it must fit the register's width.
+With an <op> of +=, -=, &=, |= or ^=, the value is combined with the
+register's current value instead, wrapping around within its width.
+8-bit: A F B C D E H L IXH IXL IYH IYL MB. 16-bit: AF SPS. 24-bit: SPL.
+BC DE HL IX IY SP and PC are 24-bit in ADL mode and 16-bit in Z80 mode.
+Note that this emulator's debugger interface can not yet write registers.
+
+Example: reg hl $40000
+         reg hl += 4
+         reg f ^= 1",
+        parse: parse_set_register },
+    Command { names: &["registers"], help: "\
+registers [verbose]
+
+Show CPU registers. With 'verbose', each register is shown on its own line
+in hex, decimal and, for 8-bit registers, binary, grouped into main, index,
+stack and control registers.",
+        parse: parse_registers },
+    Command { names: &["replay"], help: "\
+replay <file>
+
+Run the commands in a recording made by record again, in order, so the CPU
+is sent the same requests. Timings are not kept, and record and replay
+commands in the recording are skipped.
+
+Example: replay crash.log",
+        parse: parse_replay },
+    Command { names: &["save-state"], help: "\
+save-state <file> [start] [len]
+
+Save the CPU state, registers, breakpoints, <len> bytes of memory from
+<start> and the recent command history to a text file, eg: to attach to a
+bug report. <start> defaults to PC and <len> to $100.
+
+Example: save-state crash.txt $40000 $1000",
+        parse: parse_save_state },
+    Command { names: &["set"], help: "\
+set <name> <value>
+
+Change a debugger setting. Settings:
+
+address-width 24|auto
+    With 'auto', code addresses in disassembly, CPU state and step output
+    are shown as 4 hex digits when the CPU is in Z80 (ADL=0) mode, as the
+    16-bit offset from MB. Default: 24
+
+bell on|off
+    Ring the terminal bell when a breakpoint pauses the CPU, for when the
+    terminal isn't being watched. Default: off
+
+confirm on|off
+    Ask before running commands that throw away state that can't be got
+    back, such as 'delete' with no addresses, which removes all
+    breakpoints, or that would flood the terminal, such as disassembling
+    more than 4KB. Turn it off for scripts. Default: on
+
+detect-smc on|off
+    Watch for self-modifying code: while stepping, check the code shown by
+    disassembly listings since it was turned on (except ranges marked as
+    data) for writes, and report each one as 'Write to code at $X'. Costs
+    a read of all that code per step, so at most 64KB is watched; turning
+    it off forgets it. Default: off
+
+disasm-bytes on|off|left
+    Whether disassembly shows each instruction's opcode bytes: after the
+    instruction, not at all, or in a fixed-width column between the
+    address and the instruction. Default: on
+
+disasm-case keep|upper|lower
+    Change the case of disassembled instructions after 'disasm-syntax':
+    mnemonics and registers, but not hex numbers or quoted strings.
+    Default: keep
+
+disasm-syntax native|zilog|gnu
+    How disassembly is written. 'native' is the emulator's own output,
+    'zilog' is upper-case with hex numbers as 0FFh, and 'gnu' is lower-case
+    with hex numbers as 0xff. Default: native
+
+disasm-values on|off
+    Follow instructions that read or write a fixed address, such as
+    ld a,($40000), with the value there now, eg: ; =$7f. Each one costs a
+    memory read (see mem-read-cache). Default: off
+
+dumpsize <bytes>
+    Default length of a memory dump. Default: 16
+
+frame-layout <name>,<name>,...
+    Names of the stack words shown by examine-stack-frame, from SP
+    upwards. Default: ret
+
+history-dedup on|off
+    Leave a command out of the history when it repeats the one before.
+    Default: on
+
+history-size <n>
+    Most commands kept in the command history. Default: 100
+
+input-echo on|off
+    Print each command, after the prompt, before running it. When commands
+    are piped into the debugger they are not echoed, so this makes the
+    output read like an interactive session. Default: off
+
+listsize <bytes>
+    Default length of a disassembly listing. Default: $20
+
+mem-read-cache on|off
+    While paused, remember memory already read by x, print, mem-verify,
+    etc, instead of fetching it again. The cache is cleared whenever the
+    CPU runs or a setting changes. Default: off
+
+notify-command <command>|off
+    Run <command> with the shell, without waiting for it, when a breakpoint
+    pauses the CPU, eg: to show a desktop notification. Default: off
+
+pc-arrow on|off
+    Mark the instruction at PC in disassembly listings with => instead of
+    *, to make it easier to spot. Default: off
+
+physical-pc on|off
+    In Z80 mode, follow PC in the CPU state and registers with the physical
+    address it refers to, as (phys=MB:PC). Default: off
+
+response-timeout <ms>
+    How long to wait for the CPU to answer a command, such as mem or step,
+    before giving up with 'CPU did not respond' and returning to the
+    prompt. Default: 10000
+
+skip-range <start> <end>|off
+    Add a range of addresses, such as MOS, that step steps over calls and
+    rsts into, as next would. Set it again to add more ranges, or to 'off'
+    to remove them all. List them with info skip. Default: off
+
+stack-bytes <n>|auto
+    How many bytes of stack the CPU state shows, rounded up to whole words
+    (3 bytes in ADL mode, 2 in Z80 mode). Any beyond what the CPU sends with
+    its state are read from memory and shown on the following line. At
+    most 256. With 'auto', as many as the CPU sends. Default: auto
+
+state-stack on|off
+    Whether the CPU state shown by state, and when the CPU pauses, ends
+    with the words on top of the stack, labelled with their offsets from
+    SP: 3 bytes each in ADL mode, 2 in Z80 mode. Default: on
+
+step-output full|compact|none
+    What step and next show after each instruction: the full CPU state,
+    just PC and the instruction there, or nothing. Default: full
+
+word-endian little|big
+    Byte order of the halfwords and words shown by x. The eZ80 is
+    little-endian, but big can help when reading foreign data. Default: little
+
+Settings are saved to ~/.agondbg_settings when the debugger exits, and
+loaded from it when it starts.
+
+Example: set disasm-syntax zilog",
+        parse: parse_set },
+    Command { names: &["show"], help: "\
+show [name]
+
+Show the current value of the setting <name>, or of all settings. See
+help set for what each one does.
+
+Example: show listsize",
+        parse: parse_show },
+    Command { names: &["state", "."], help: "\
+state [regs|full]
+
+Show CPU state: the instruction at PC, registers and the top of the stack.
+With 'regs', the stack is left out, and with 'full' a longer dump of the
+stack follows. The 'state-stack' setting leaves the stack out by default
+(see help set).",
+        parse: parse_state },
+    Command { names: &["step", "s"], help: "\
+step [count]
+
+Execute one instruction, or <count> instructions. What is shown after each
+one depends on the 'step-output' setting, and calls into a 'skip-range'
+are stepped over (see help set).",
+        parse: parse_step },
+    Command { names: &["step-branch"], help: "\
+step-branch
+
+Single-step until the CPU takes a jump, call or return (ie: PC does not
+move on to the following instruction), then show the branch instruction,
+its target and the CPU state. Straight-line code is skipped quickly.",
+        parse: parse_step_branch },
+    Command { names: &["step-to-write"], help: "\
+step-to-write <address>
+
+Single-step until an instruction changes the byte at <address>, then show
+the instruction, the old and new values, the number of steps taken and the
+CPU state. The byte is compared after each step, so a write of the value
+already there is not noticed. Gives up after a million steps.
+
+Example: step-to-write $40000",
+        parse: parse_step_to_write },
+    Command { names: &["struct"], help: "\
+struct define <name> <type> <field> [<type> <field> ...]
+
+Define a struct layout for mem-struct. Types are u8, u16, u24 and u32,
+unsigned and little-endian like the eZ80. Defining a name again replaces it.
+
+Example: struct define Sprite u16 x u16 y u8 flags",
+        parse: parse_struct },
+    Command { names: &["time"], help: "\
+time <start> <end> [timeout_ms]
+
+Count the instructions the CPU executes from <start> to <end>. Unless it is
+already at <start>, the CPU is resumed until it gets there (giving up after
+the timeout, default 10000ms, or if a breakpoint is hit first). It is then
+single-stepped until PC reaches <end>, and the number of instructions is
+shown. The emulator has no cycle counter, so cycles and time are not
+measured. Press Ctrl-C to stop stepping.
+
+Example: time draw_sprite draw_sprite_end",
+        parse: parse_time },
+    Command { names: &["toggle"], help: "\
+toggle <address>
+
+Delete the breakpoint or trigger at <address> if there is one, or set a
+breakpoint there if not, and say which was done.
+
+Example: toggle $40000",
+        parse: parse_toggle },
+    Command { names: &["trace"], help: "\
+trace on|off
+
+Enable or disable logging of every instruction executed.",
+        parse: parse_trace },
+    Command { names: &["trigger"], help: "\
+trigger <address> cmd1 : cmd2 : ...
+
+Perform debugger commands when <address> is reached. A quoted string as a
+command prints that message. If a pause is followed by continue, the CPU
+carries on by itself after the commands in between, without waiting at
+the debugger prompt.
+
+Example: trigger $123 pause:state:continue
+
+break $123 is equivalent to:
+    trigger $123 pause:\"CPU paused at breakpoint\":state",
+        parse: parse_trigger },
+    Command { names: &["triggers"], help: "\
+triggers
+
+List triggers.",
+        parse: parse_triggers },
+    Command { names: &["vdp-dump"], help: "\
+vdp-dump <file> <address> <width> <height> [rgba|rgb222]
+
+Render <width> x <height> pixels of graphics data in eZ80 memory, starting
+at <address>, to a PPM image file. The VDP's own framebuffer lives on the
+ESP32 and is not visible to the eZ80, so this is for bitmap data before it
+is uploaded. Pixel formats:
+
+rgba      4 bytes per pixel, as sent with VDU 23,27,1 (default)
+rgb222    1 byte per pixel, 2 bits each of red, green and blue
+
+Example: vdp-dump sprite.ppm $50000 16 16",
+        parse: parse_vdp_dump },
+    Command { names: &["wait-break"], help: "\
+wait-break [timeout_ms]
+
+Resume the CPU and wait until a breakpoint pauses it, or until the timeout
+(default 10000ms) runs out, in which case the CPU is paused again. Reports
+where it stopped. Useful in scripts of debugger commands.
+
+Example: wait-break 2000",
+        parse: parse_wait_break },
+    Command { names: &["wait-idle"], help: "\
+wait-idle [timeout_ms]
+
+Resume the CPU, and sample PC every 100ms while it runs. When a second's
+worth of samples all fall within 64 bytes, the CPU is taken to be stuck in
+an idle or hung loop: it is paused, and the loop's address range and the
+CPU state are shown. Also stops at breakpoints, or pauses when the timeout
+(default 10000ms) runs out. Loops that call into MOS, such as waiting for a
+key, spread their samples wider and may not be noticed.
+
+Example: wait-idle 30000",
+        parse: parse_wait_idle },
+    Command { names: &["watch-expr"], help: "\
+watch-expr <expression>
+
+Single-step until the value of <expression> changes, then show the old and
+new values. Expressions may use + - * / % & | ^ << >> ~ and brackets, and
+[address] reads the byte at that address.
+
+Example: watch-expr [$40000] + [$40001] * 256",
+        parse: parse_watch_expr },
+    Command { names: &["watch-range"], help: "\
+watch-range <start> <end>
+watch-range <start>..<end>
+
+Single-step until an instruction changes any byte from <start> up to <end>,
+eg: a struct or buffer, then show the instruction, each changed byte's
+offset, address and old and new values, and the CPU state. The range is
+compared after each step, so it is best kept small. Gives up after a
+million steps; press Ctrl-C to stop sooner.
+
+Example: watch-range $40000 $40100",
+        parse: parse_watch_range },
+    Command { names: &["x"], help: "\
+x <address> [count] [b|h|w]
+
+Examine memory as <count> units (default 8) of: b, bytes; h, 16-bit
+halfwords; or w, 24-bit words (the default). Halfwords and words are
+shown in the byte order of the 'word-endian' setting.
+
+Example: x $40000 4 h",
+        parse: parse_examine },
+];
+
+fn command_help(cmd: &Command) -> String {
+    if cmd.names.len() > 1 {
+        format!("{}\n\nAliases: {}", cmd.help, cmd.names[1..].join(", "))
+    } else {
+        cmd.help.to_string()
+    }
+}
+
+fn find_command(name: &str) -> Result<&'static Command, ParseError> {
+    if let Some(cmd) = COMMANDS.iter().find(|c| c.names.contains(&name)) {
+        return Ok(cmd);
+    }
+    let candidates: Vec<&'static Command> = COMMANDS.iter()
+        .filter(|c| c.names[0].starts_with(name))
+        .collect();
+    match candidates[..] {
+        [] => Err(ParseError::UnknownCommand(name.to_string())),
+        [cmd] => Ok(cmd),
+        _ => Err(ParseError::AmbiguousCommand {
+            name: name.to_string(),
+            matches: candidates.iter().map(|c| c.names[0].to_string()).collect(),
+        })
+    }
+}
+
+pub fn parse_cmd(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    match tokens.next() {
+        Some(tok) if tok.starts_with('"') => {
+            // string token
+            expect_end_of_cmd(tokens)?;
+            Ok(Cmd::Core(DebugCmd::Message(tok.to_string())))
+        }
+        Some(tok) if tok.starts_with('$') => parse_assignment(tok, tokens),
+        Some(tok) => (find_command(tok)?.parse)(tokens, ctx),
+        None => Ok(Cmd::End)
+    }
+}
+
+/// `$name = <expression>`
+fn parse_assignment(first: &str, tokens: &mut Tokens) -> Result<Cmd, ParseError> {
+    let text = format!("{} {}", first, rest_of_cmd(tokens));
+    match text.split_once('=') {
+        Some((name, value)) => {
+            let name = name.trim().trim_start_matches('$');
+            Ok(Cmd::UiAssign(name.to_string(), Expr::parse(value)?))
+        }
+        None => Err("$name = <expression>".into())
+    }
+}
+
+fn parse_triggers(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::Core(DebugCmd::ListTriggers))
+}
+
+fn parse_trigger(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    if let Some(addr) = parse_number(tokens, ctx) {
+        let mut actions = vec![];
+        loop {
+            match parse_cmd(tokens, ctx)? {
+                Cmd::Core(a @ DebugCmd::AddTrigger(_)) => {
+                    return Err(format!("Invalid action to trigger: {:?}", a).into());
                 }
-            }
-            "." | "state" => {
-                expect_end_of_cmd(tokens)?;
-                Ok(Cmd::Core(DebugCmd::GetState))
-            }
-            mode @ ("dis16" | "dis24" | "dis" | "disassemble") => {
-                let adl = match mode {
-                    "dis16" => Some(false),
-                    "dis24" => Some(true),
-                    _ => None
-                };
-                let start = parse_number(tokens);
-                if let Some(start) = start {
-                    let end = parse_number(tokens).unwrap_or(start + 0x20);
-                    expect_end_of_cmd(tokens)?;
-                    Ok(Cmd::Core(DebugCmd::Disassemble { adl, start, end }))
-                } else {
-                    Ok(Cmd::Core(DebugCmd::DisassemblePc { adl }))
+                Cmd::Core(a) => actions.push(a),
+                Cmd::End => break,
+                a => {
+                    return Err(format!("Invalid action to trigger: {:?}", a).into());
                 }
             }
-            "c" | "continue" => {
-                expect_end_of_cmd(tokens)?;
-                Ok(Cmd::Core(DebugCmd::Continue))
-            }
-            _ => {
-                if tok.chars().nth(0) == Some('"') {
-                    // string token
-                    expect_end_of_cmd(tokens)?;
-                    Ok(Cmd::Core(DebugCmd::Message(tok.to_string())))
-                } else {
-                    Err(format!("Unknown command: {}", tok))
-                }
+            if let Some(&t) = tokens.peek() {
+                if t != ":" { break }
+                else { tokens.next(); }
             }
         }
+        expect_end_of_cmd(tokens)?;
+        let paused = actions.iter().position(|a| matches!(a, DebugCmd::Pause));
+        if paused.is_some_and(|p| actions[p..].iter().any(|a| matches!(a, DebugCmd::Continue))) {
+            actions.insert(0, DebugCmd::Message(AUTO_CONTINUE_MESSAGE.to_string()));
+        }
+        let trigger = DebugCmd::AddTrigger(Trigger {
+            address: addr,
+            once: false,
+            actions
+        });
+        Ok(Cmd::Core(trigger))
     } else {
-        Ok(Cmd::End)
+        Err("trigger expects an address argument".into())
     }
 }
 
+fn parse_pause(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::Core(DebugCmd::Pause))
+}
+
+fn parse_help(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let topic = match tokens.peek() {
+        Some(&t) if t != ":" => {
+            tokens.next();
+            Some(command_help(find_command(t)?))
+        }
+        _ => None
+    };
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiHelp(topic))
+}
+
+/// Topics of the info command, listed when the topic is unknown or missing
+const INFO_TOPICS: &[&str] = &["all-registers", "breakpoints", "cpu", "data", "line", "logpoints", "skip", "variables", "vdp"];
+
+fn parse_info(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    match tokens.next() {
+        Some("all-registers") => {
+            expect_end_of_cmd(tokens)?;
+            Ok(Cmd::UiAllRegisters)
+        }
+        Some("breakpoints") => {
+            expect_end_of_cmd(tokens)?;
+            Ok(Cmd::Core(DebugCmd::ListTriggers))
+        }
+        Some("cpu") => {
+            expect_end_of_cmd(tokens)?;
+            Ok(Cmd::UiCpuInfo)
+        }
+        Some("data") => {
+            expect_end_of_cmd(tokens)?;
+            Ok(Cmd::UiDataRanges)
+        }
+        Some("line") => {
+            let addr = parse_number(tokens, ctx).ok_or("info line <address>")?;
+            expect_end_of_cmd(tokens)?;
+            Ok(Cmd::UiInfoLine(addr))
+        }
+        Some("logpoints") => {
+            expect_end_of_cmd(tokens)?;
+            Ok(Cmd::UiLogpoints)
+        }
+        Some("skip") => {
+            expect_end_of_cmd(tokens)?;
+            Ok(Cmd::UiSkipRanges)
+        }
+        Some("variables") => {
+            expect_end_of_cmd(tokens)?;
+            Ok(Cmd::UiVariables)
+        }
+        Some("vdp") => {
+            let sysvars = parse_number(tokens, ctx);
+            expect_end_of_cmd(tokens)?;
+            Ok(Cmd::UiVdpInfo(sysvars))
+        }
+        Some(topic) => Err(format!("Unknown info topic '{}'. Topics: {}", topic, INFO_TOPICS.join(", ")).into()),
+        None => Err(format!("info <topic>. Topics: {}", INFO_TOPICS.join(", ")).into()),
+    }
+}
+
+fn parse_delete(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let mut addrs = vec![];
+    while let Some(addr) = parse_number(tokens, ctx) {
+        addrs.push(addr);
+    }
+    expect_end_of_cmd(tokens)?;
+    match addrs[..] {
+        [] => Ok(Cmd::UiDeleteAll),
+        [addr] => Ok(Cmd::Core(DebugCmd::DeleteTrigger(addr))),
+        _ => Ok(Cmd::UiDelete(addrs))
+    }
+}
+
+fn parse_toggle(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let address = parse_number(tokens, ctx).ok_or("toggle <address>")?;
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiToggle(address))
+}
+
+/// Printed when a breakpoint set with break pauses the CPU
+pub const BREAKPOINT_MESSAGE: &str = "CPU paused at breakpoint";
+
+/// Sent first by a trigger that pauses the CPU and then continues it, so
+/// that the pause doesn't drop into the debugger prompt
+pub const AUTO_CONTINUE_MESSAGE: &str = "\u{1}auto-continue";
+
+fn parse_break(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    if let Some(offset) = tokens.peek().and_then(|t| pc_offset(t)) {
+        tokens.next();
+        expect_end_of_cmd(tokens)?;
+        return Ok(Cmd::UiBreakAtPc { offset });
+    }
+    let mut addrs = vec![];
+    loop {
+        if let Some(addr) = parse_source_location(tokens, ctx)? {
+            addrs.push(addr);
+        } else if let Some(addr) = parse_number(tokens, ctx) {
+            addrs.push(addr);
+        } else {
+            break;
+        }
+    }
+    let usage = "break <address> [address ...] [after <n>] [from <caller>] [show dis]";
+    if addrs.is_empty() {
+        expect_end_of_cmd(tokens).map_err(|_| usage)?;
+        return Ok(Cmd::UiBreakAtPc { offset: 0 });
+    }
+    let after = if parse_exact(tokens, "after") {
+        Some(parse_number(tokens, ctx).filter(|&n| n > 0).ok_or(usage)?)
+    } else {
+        None
+    };
+    let from = if parse_exact(tokens, "from") {
+        Some(parse_number(tokens, ctx).ok_or(usage)?)
+    } else {
+        None
+    };
+    let show_dis = parse_exact(tokens, "show");
+    if show_dis && !parse_exact(tokens, "dis") {
+        return Err(usage.into());
+    }
+    expect_end_of_cmd(tokens)?;
+
+    if after.is_some() || from.is_some() {
+        return Ok(Cmd::UiConditionalBreak { addresses: addrs, after: after.unwrap_or(1), from, show_dis });
+    }
+    let mut triggers: Vec<Trigger> = addrs.into_iter().map(|address| breakpoint(address, show_dis)).collect();
+    if triggers.len() == 1 {
+        Ok(Cmd::Core(DebugCmd::AddTrigger(triggers.remove(0))))
+    } else {
+        Ok(Cmd::UiBreakpoints(triggers))
+    }
+}
+
+/// The offset from PC of an address written relative to it, as pc, pc+n,
+/// pc-n, +n or -n
+fn pc_offset(token: &str) -> Option<i64> {
+    let lower = token.to_ascii_lowercase();
+    let rest = match lower.strip_prefix("pc") {
+        Some("") => return Some(0),
+        Some(rest) => rest,
+        None => &lower,
+    };
+    if let Some(n) = rest.strip_prefix('+') {
+        number_from_str(n).map(i64::from)
+    } else {
+        rest.strip_prefix('-').and_then(number_from_str).map(|n| -i64::from(n))
+    }
+}
+
+/// The trigger for a plain breakpoint at `address`
+pub fn breakpoint(address: u32, show_dis: bool) -> Trigger {
+    let mut actions = vec![
+        DebugCmd::Pause,
+        DebugCmd::Message(BREAKPOINT_MESSAGE.to_string()),
+        DebugCmd::GetState,
+    ];
+    if show_dis {
+        actions.push(DebugCmd::DisassemblePc { adl: None });
+    }
+    Trigger { address, once: false, actions }
+}
+
+/// Parse a source location written as <file>:<line>, which tokenizes as
+/// three tokens, into the address of its code
+fn parse_source_location(tokens: &mut Tokens, ctx: &Context) -> Result<Option<u32>, String> {
+    let mut ahead = tokens.clone();
+    let (Some(file), Some(":"), Some(line)) = (ahead.next(), ahead.next(), ahead.next()) else {
+        return Ok(None);
+    };
+    let Ok(line) = line.parse::<u32>() else {
+        return Ok(None);
+    };
+    if number_from_str(file).is_some() {
+        return Ok(None);
+    }
+    let addr = ctx.lines.address_of(file, line).ok_or(format!("No code known at {}:{} (see help load-lines)", file, line))?;
+    *tokens = ahead;
+    Ok(Some(addr))
+}
+
+fn parse_stack_frame(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiStackFrame)
+}
+
+fn parse_break_adl_change(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiBreakAdlChange)
+}
+
+fn parse_break_depth(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let max_depth = parse_number(tokens, ctx).ok_or("break-depth <n>")?;
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiBreakDepth(max_depth))
+}
+
+fn parse_clear(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiClear)
+}
+
+fn parse_finish(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let stop_on_call = parse_exact(tokens, "calls");
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiFinish { stop_on_call })
+}
+
+fn parse_fix_checksum(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "fix-checksum <start> <len> <address> [sum8|crc32]";
+    let start = parse_number(tokens, ctx).ok_or(usage)?;
+    let len = parse_number(tokens, ctx).ok_or(usage)?;
+    let field = parse_number(tokens, ctx).ok_or(usage)?;
+    let algorithm = match tokens.peek() {
+        Some(&name) if name != ":" => {
+            tokens.next();
+            Algorithm::from_name(name).ok_or(usage)?
+        }
+        _ => Algorithm::Sum8,
+    };
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiFixChecksum { start, len, field, algorithm })
+}
+
+fn parse_exit(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiExit)
+}
+
+fn parse_next(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::Core(DebugCmd::StepOver))
+}
+
+fn parse_step(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let count = parse_number(tokens, ctx);
+    expect_end_of_cmd(tokens)?;
+    match count {
+        Some(count) => Ok(Cmd::UiStep(count)),
+        None => Ok(Cmd::Core(DebugCmd::Step)),
+    }
+}
+
+fn parse_step_branch(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiStepBranch)
+}
+
+fn parse_step_to_write(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let address = parse_number(tokens, ctx).ok_or("step-to-write <address>")?;
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiStepToWrite(address))
+}
+
+fn parse_trace(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    if parse_exact(tokens, "on") {
+        expect_end_of_cmd(tokens)?;
+        Ok(Cmd::Core(DebugCmd::SetTrace(true)))
+    }
+    else if parse_exact(tokens, "off") {
+        expect_end_of_cmd(tokens)?;
+        Ok(Cmd::Core(DebugCmd::SetTrace(false)))
+    }
+    else {
+        Err("expected 'on' or 'off'".into())
+    }
+}
+
+fn parse_print(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let text = rest_of_cmd(tokens);
+    if text.is_empty() {
+        return Err("print <expression>".into());
+    }
+    Ok(Cmd::UiPrint(Expr::parse(&text)?))
+}
+
+fn parse_set_register(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "reg <register> [+= | -= | &= | |= | ^=] <value>";
+    let name = parse_string(tokens).ok_or(usage)?;
+    let adjust = tokens.peek().and_then(|t| Adjust::from_token(t));
+    if adjust.is_some() {
+        tokens.next();
+    }
+    let value = parse_number(tokens, ctx).ok_or(usage)?;
+    expect_end_of_cmd(tokens)?;
+    match adjust {
+        Some(adjust) => Ok(Cmd::UiAdjustRegister(name, adjust, value)),
+        None => Ok(Cmd::UiSetRegister(name, value)),
+    }
+}
+
+fn parse_registers(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let verbose = parse_exact(tokens, "verbose");
+    expect_end_of_cmd(tokens)?;
+    if verbose {
+        return Ok(Cmd::UiRegistersVerbose);
+    }
+    Ok(Cmd::Core(DebugCmd::GetRegisters))
+}
+
+fn parse_memory(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let (start, len) = if let Some((start, end)) = parse_range(tokens, ctx)? {
+        (start, end - start)
+    } else if let Some(start) = parse_number(tokens, ctx) {
+        (start, parse_number(tokens, ctx).unwrap_or(ctx.settings.dumpsize))
+    } else {
+        return Err("mem <start> [len] [vdu]".into());
+    };
+    let vdu = parse_exact(tokens, "vdu");
+    expect_end_of_cmd(tokens)?;
+    if vdu {
+        Ok(Cmd::UiMemoryVdu { start, len })
+    } else {
+        Ok(Cmd::Core(DebugCmd::GetMemory { start, len }))
+    }
+}
+
+/// Default length of the memory saved by save-state
+const SAVE_STATE_MEMORY_LEN: u32 = 0x100;
+
+fn parse_save_state(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let path = parse_string(tokens).ok_or("save-state <file> [start] [len]")?;
+    let start = parse_number(tokens, ctx);
+    let len = parse_number(tokens, ctx).unwrap_or(SAVE_STATE_MEMORY_LEN);
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiSaveState { path, start, len })
+}
+
+fn parse_set(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let name = tokens.next().filter(|&t| t != ":");
+    let value = rest_of_cmd(tokens);
+    match name {
+        Some(name) if !value.is_empty() => Ok(Cmd::UiSet(name.to_string(), value)),
+        _ => Err("set <name> <value>".into())
+    }
+}
+
+fn parse_show(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let name = tokens.next().filter(|&t| t != ":");
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiShow(name.map(str::to_string)))
+}
+
+fn parse_struct(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "struct define <name> <type> <field> [<type> <field> ...]";
+    if !parse_exact(tokens, "define") {
+        return Err(usage.into());
+    }
+    let name = tokens.next().filter(|&t| t != ":").ok_or(usage)?;
+    let fields = rest_of_cmd(tokens);
+    let layout = Layout::parse(&fields.split_whitespace().collect::<Vec<&str>>())?;
+    Ok(Cmd::UiStructDefine(name.to_string(), layout))
+}
+
+fn parse_mark_data(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "mark-data <start> <end> | <start>..<end> | off";
+    if parse_exact(tokens, "off") {
+        expect_end_of_cmd(tokens)?;
+        return Ok(Cmd::UiMarkData(None));
+    }
+    let range = match parse_range(tokens, ctx)? {
+        Some(range) => range,
+        None => {
+            let start = parse_number(tokens, ctx).ok_or(usage)?;
+            let end = parse_number(tokens, ctx).filter(|&end| end > start).ok_or(usage)?;
+            (start, end)
+        }
+    };
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiMarkData(Some(range)))
+}
+
+fn parse_logpoint(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "logpoint <address> \"<message>\" [limit <n>]";
+    let address = parse_number(tokens, ctx).ok_or(usage)?;
+    let message = parse_string(tokens).ok_or(usage)?;
+    let limit = if parse_exact(tokens, "limit") {
+        Some(parse_number(tokens, ctx).ok_or(usage)?)
+    } else {
+        None
+    };
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiLogpoint { address, message, limit })
+}
+
+fn parse_mem_struct(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "mem-struct <name> <address> [count]";
+    let name = tokens.next().filter(|&t| t != ":").ok_or(usage)?;
+    let address = parse_number(tokens, ctx).ok_or(usage)?;
+    let count = parse_number(tokens, ctx).unwrap_or(1);
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiMemStruct { name: name.to_string(), address, count })
+}
+
+fn parse_mem_verify(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "mem-verify <file> <address>";
+    let path = parse_string(tokens).ok_or(usage)?;
+    let address = parse_number(tokens, ctx).ok_or(usage)?;
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiMemVerify { path, address })
+}
+
+/// The range find-string searches by default: all of RAM
+const FIND_STRING_RANGE: (u32, u32) = (0x40000, 0xc0000);
+
+fn parse_find_string(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "find-string \"<text>\" [<start> <end>] [nocase] [context]";
+    let text = parse_string(tokens).filter(|text| !text.is_empty()).ok_or(usage)?;
+    if !text.is_ascii() {
+        return Err("find-string only searches for ASCII text".into());
+    }
+    let (start, end) = match parse_range(tokens, ctx)? {
+        Some(range) => range,
+        None => match parse_number(tokens, ctx) {
+            Some(start) => (start, parse_number(tokens, ctx).ok_or(usage)?),
+            None => FIND_STRING_RANGE,
+        }
+    };
+    if end <= start {
+        return Err(format!("Invalid range: ${:x} ${:x} (the end must be after the start)", start, end).into());
+    }
+    let (mut nocase, mut context) = (false, false);
+    loop {
+        if parse_exact(tokens, "nocase") {
+            nocase = true;
+        } else if parse_exact(tokens, "context") {
+            context = true;
+        } else {
+            break;
+        }
+    }
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiFindString { text, start, end, nocase, context })
+}
+
+fn parse_watch_range(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "watch-range <start> <end>";
+    let (start, end) = match parse_range(tokens, ctx)? {
+        Some(range) => range,
+        None => (parse_number(tokens, ctx).ok_or(usage)?, parse_number(tokens, ctx).ok_or(usage)?),
+    };
+    if end <= start {
+        return Err(format!("Invalid range: ${:x} ${:x} (the end must be after the start)", start, end).into());
+    }
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiWatchRange { start, end })
+}
+
+fn parse_mem_wait(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "mem-wait <address> <value>";
+    let address = parse_number(tokens, ctx).ok_or(usage)?;
+    let value = parse_number(tokens, ctx).ok_or(usage)?;
+    let value = u8::try_from(value).map_err(|_| format!("mem-wait value ${:x} is not a byte", value))?;
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiMemWait { address, value })
+}
+
+fn parse_state(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let cmd = if parse_exact(tokens, "regs") {
+        Cmd::UiStateRegs
+    } else if parse_exact(tokens, "full") {
+        Cmd::UiStateFull
+    } else {
+        Cmd::Core(DebugCmd::GetState)
+    };
+    expect_end_of_cmd(tokens)?;
+    Ok(cmd)
+}
+
+fn parse_disassemble_function(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiDisassembleFunction)
+}
+
+fn parse_load_lines(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let path = parse_string(tokens).ok_or("load-lines <file>")?;
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiLoadLines(path))
+}
+
+fn parse_load_symbols(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let path = parse_string(tokens).ok_or("load-symbols <file>")?;
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiLoadSymbols(path))
+}
+
+fn parse_record(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let path = parse_string(tokens).ok_or("record <file>|off")?;
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiRecord(if path == "off" { None } else { Some(path) }))
+}
+
+fn parse_replay(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let path = parse_string(tokens).ok_or("replay <file>")?;
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiReplay(path))
+}
+
+fn parse_dis16(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    parse_disassembly_range(tokens, ctx, Some(false))
+}
+
+fn parse_dis24(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    parse_disassembly_range(tokens, ctx, Some(true))
+}
+
+fn parse_disassemble(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    parse_disassembly_range(tokens, ctx, None)
+}
+
+fn parse_disassembly_range(tokens: &mut Tokens, ctx: &Context, adl: Option<bool>) -> Result<Cmd, ParseError> {
+    // a symbol on its own lists up to the next one, ie: the whole routine
+    let symbol_end = tokens.peek()
+        .filter(|&&t| number_from_str(t).is_none() && !t.starts_with('$'))
+        .and_then(|&t| ctx.symbols.address(t))
+        .and_then(|addr| ctx.symbols.containing(addr)?.2);
+    let cmd = if let Some((start, end)) = parse_range(tokens, ctx)? {
+        DebugCmd::Disassemble { adl, start, end }
+    } else if let Some(start) = parse_number(tokens, ctx) {
+        let end = parse_number(tokens, ctx)
+            .or(symbol_end)
+            .unwrap_or(start.saturating_add(ctx.settings.listsize).min(0xffffff));
+        DebugCmd::Disassemble { adl, start, end }
+    } else if parse_exact(tokens, "-") {
+        let (start, _) = ctx.last_disassembly.ok_or("No disassembly to go back from".to_string())?;
+        DebugCmd::Disassemble { adl, start: start.saturating_sub(ctx.settings.listsize), end: start }
+    } else if let Some((_, end)) = ctx.last_disassembly {
+        DebugCmd::Disassemble { adl, start: end, end: end.saturating_add(ctx.settings.listsize).min(0xffffff) }
+    } else {
+        DebugCmd::DisassemblePc { adl }
+    };
+
+    let mut options = DisasmOptions::default();
+    let mut has_options = false;
+    loop {
+        if parse_exact(tokens, "skip-padding") {
+            options.skip_padding = true;
+        } else if parse_exact(tokens, "reassemblable") {
+            options.reassemblable = true;
+        } else if parse_exact(tokens, "follow") {
+            options.follow = parse_number(tokens, ctx).ok_or("follow <depth>")?;
+        } else if parse_exact(tokens, "mos") {
+            options.mos = true;
+        } else if parse_exact(tokens, "mixed") {
+            options.mixed = true;
+        } else if parse_exact(tokens, "json") {
+            options.json = true;
+        } else if parse_exact(tokens, "bare") {
+            options.bare = true;
+        } else if parse_exact(tokens, "nolocs") {
+            options.nolocs = true;
+        } else if parse_exact(tokens, "blocks") {
+            options.blocks = true;
+        } else if parse_exact(tokens, "clip") {
+            options.clip = true;
+        } else {
+            break;
+        }
+        has_options = true;
+    }
+    expect_end_of_cmd(tokens)?;
+
+    if has_options {
+        Ok(Cmd::UiDisassemble(cmd, options))
+    } else {
+        Ok(Cmd::Core(cmd))
+    }
+}
+
+fn parse_vdp_dump(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "vdp-dump <file> <address> <width> <height> [rgba|rgb222]";
+    let path = parse_string(tokens).ok_or(usage)?;
+    let address = parse_number(tokens, ctx).ok_or(usage)?;
+    let width = parse_number(tokens, ctx).ok_or(usage)?;
+    let height = parse_number(tokens, ctx).ok_or(usage)?;
+    let format = match tokens.peek() {
+        Some(&name) if name != ":" => {
+            tokens.next();
+            PixelFormat::from_name(name).ok_or(format!("Unknown pixel format: {}", name))?
+        }
+        _ => PixelFormat::Rgba8888
+    };
+    expect_end_of_cmd(tokens)?;
+    if vdp::data_len(width, height, format).is_none() {
+        return Err(format!("A {}x{} image is more than the 16MB address space", width, height).into());
+    }
+    Ok(Cmd::UiVdpDump { path, address, width, height, format })
+}
+
+fn parse_examine(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let start = parse_number(tokens, ctx).ok_or("x <address> [count] [b|h|w]")?;
+    let count = parse_number(tokens, ctx).unwrap_or(8);
+    let unit = if parse_exact(tokens, "b") {
+        1
+    } else if parse_exact(tokens, "h") {
+        2
+    } else {
+        parse_exact(tokens, "w");
+        3
+    };
+    expect_end_of_cmd(tokens)?;
+    if count.checked_mul(unit).is_none_or(|len| len > ADDRESS_SPACE_LEN) {
+        return Err(format!("{} units of {} bytes is more than the 16MB address space", count, unit).into());
+    }
+    Ok(Cmd::UiExamine { start, count, unit })
+}
+
+/// How long wait-break waits by default, in milliseconds
+const WAIT_BREAK_TIMEOUT_MS: u32 = 10_000;
+
+fn parse_wait_break(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let timeout = parse_number(tokens, ctx).unwrap_or(WAIT_BREAK_TIMEOUT_MS);
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiWaitBreak(timeout))
+}
+
+fn parse_time(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let usage = "time <start> <end> [timeout_ms]";
+    let start = parse_number(tokens, ctx).ok_or(usage)?;
+    let end = parse_number(tokens, ctx).ok_or(usage)?;
+    let timeout_ms = parse_number(tokens, ctx).unwrap_or(WAIT_BREAK_TIMEOUT_MS);
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiTime { start, end, timeout_ms })
+}
+
+fn parse_wait_idle(tokens: &mut Tokens, ctx: &Context) -> Result<Cmd, ParseError> {
+    let timeout = parse_number(tokens, ctx).unwrap_or(WAIT_BREAK_TIMEOUT_MS);
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiWaitIdle(timeout))
+}
+
+fn parse_watch_expr(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let text = rest_of_cmd(tokens);
+    if text.is_empty() {
+        return Err("watch-expr <expression>".into());
+    }
+    Ok(Cmd::UiWatchExpr(Expr::parse(&text)?))
+}
+
+fn parse_break_op(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    let pattern = rest_of_cmd(tokens);
+    if pattern.is_empty() {
+        return Err("break-op <instruction>".into());
+    }
+    Ok(Cmd::UiBreakOp(pattern))
+}
+
+fn parse_continue_to_interrupt(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::UiContinueToInterrupt)
+}
+
+fn parse_continue(tokens: &mut Tokens, _ctx: &Context) -> Result<Cmd, ParseError> {
+    expect_end_of_cmd(tokens)?;
+    Ok(Cmd::Core(DebugCmd::Continue))
+}
+
 fn parse_exact(tokens: &mut Tokens, expected: &str) -> bool {
     match tokens.peek() {
         Some(&s) if s == expected => {
@@ -223,16 +1638,73 @@ fn parse_exact(tokens: &mut Tokens, expected: &str) -> bool {
     }
 }
 
-fn parse_number(tokens: &mut Tokens) -> Option<u32> {
-    if let Some(&s) = tokens.peek() {
-        let num = if s.starts_with('&') || s.starts_with('$') {
-            u32::from_str_radix(s.get(1..s.len()).unwrap_or(""), 16).ok()
+/// A word or "quoted string" argument, eg: a filename
+fn parse_string(tokens: &mut Tokens) -> Option<String> {
+    match tokens.peek() {
+        Some(&s) if s != ":" => {
+            tokens.next();
+            Some(s.strip_prefix('"').map(|s| s.strip_suffix('"').unwrap_or(s)).unwrap_or(s).to_string())
         }
-        else if s.ends_with('h') || s.ends_with('H') {
-            u32::from_str_radix(s.get(0..s.len()-1).unwrap_or(""), 16).ok()
+        _ => None
+    }
+}
+
+/// Expand a history recall: `!!` is the last command, and `!<text>` the
+/// last one starting with <text>. Recalls in the history are skipped, as
+/// they aren't commands themselves. None if `text` isn't a recall.
+pub fn recall(text: &str, history: &[String]) -> Result<Option<String>, String> {
+    let Some(prefix) = text.trim().strip_prefix('!') else {
+        return Ok(None);
+    };
+    let prefix = if prefix == "!" { "" } else { prefix };
+    history.iter().rev()
+        .find(|cmd| !cmd.starts_with('!') && cmd.starts_with(prefix))
+        .map(|cmd| Some(cmd.clone()))
+        .ok_or(if prefix.is_empty() {
+            "No command in the history".to_string()
         } else {
-            u32::from_str_radix(s, 10).ok()
-        };
+            format!("No command in the history starting with '{}'", prefix)
+        })
+}
+
+/// Parse a number in any of the notations the debugger accepts: decimal, or
+/// hex as $40000, &40000 or 40000h
+pub fn number_from_str(s: &str) -> Option<u32> {
+    if s.starts_with('&') || s.starts_with('$') {
+        u32::from_str_radix(s.get(1..s.len()).unwrap_or(""), 16).ok()
+    }
+    else if s.ends_with('h') || s.ends_with('H') {
+        u32::from_str_radix(s.get(0..s.len()-1).unwrap_or(""), 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// A number, debugger variable or symbol
+fn value_from_str(s: &str, ctx: &Context) -> Option<u32> {
+    number_from_str(s)
+        .or_else(|| s.strip_prefix('$').and_then(|name| ctx.variables.get(name)))
+        .or_else(|| ctx.symbols.address(s))
+}
+
+/// Parse an end-exclusive address range written <start>..<end>
+fn parse_range(tokens: &mut Tokens, ctx: &Context) -> Result<Option<(u32, u32)>, String> {
+    let Some((start, end)) = tokens.peek().and_then(|t| t.split_once("..")) else {
+        return Ok(None);
+    };
+    let range = value_from_str(start, ctx).zip(value_from_str(end, ctx));
+    match range {
+        Some((start, end)) if start < end => {
+            tokens.next();
+            Ok(Some((start, end)))
+        }
+        _ => Err(format!("Invalid range: {}..{} (the end must be after the start)", start, end))
+    }
+}
+
+fn parse_number(tokens: &mut Tokens, ctx: &Context) -> Option<u32> {
+    if let Some(&s) = tokens.peek() {
+        let num = value_from_str(s, ctx);
 
         if num.is_some() {
             tokens.next();
@@ -250,3 +1722,276 @@ fn test_tokenize() {
     assert_eq!(tokenize(" \"string literals!\" and other stuff."), ["\"string literals!\"", "and", "other", "stuff."]);
     assert_eq!(tokenize("\"hello\":command :cmd2"), ["\"hello\"", ":", "command", ":", "cmd2"]);
 }
+
+#[test]
+fn test_recall() {
+    let history: Vec<String> = ["mem $40000", "step", "mem $50000 32", "!mem", "x $40000"]
+        .iter().map(|s| s.to_string()).collect();
+    assert_eq!(recall("step", &history), Ok(None));
+    assert_eq!(recall("!!", &history), Ok(Some("x $40000".to_string())));
+    assert_eq!(recall("!mem", &history), Ok(Some("mem $50000 32".to_string())));
+    assert_eq!(recall("!st", &history), Ok(Some("step".to_string())));
+    assert_eq!(recall("!dis", &history), Err("No command in the history starting with 'dis'".to_string()));
+    assert!(recall("!!", &[]).is_err());
+}
+
+#[test]
+fn test_number_from_str() {
+    for (text, expected) in [
+        ("16", Some(16)),
+        ("$40000", Some(0x40000)),
+        ("&40cafe", Some(0x40cafe)),
+        ("40000h", Some(0x40000)),
+        ("0FFH", Some(0xff)),
+        ("$", None),
+        ("&", None),
+        ("h", None),
+        ("", None),
+        ("-1", None),
+        ("$ffffffff", Some(0xffffffff)),
+        ("$100000000", None),
+        ("0x10", None),
+        ("main", None),
+    ] {
+        assert_eq!(number_from_str(text), expected, "{:?}", text);
+    }
+}
+
+#[test]
+fn test_parse_number() {
+    let settings = Settings::default();
+    let mut variables = Variables::default();
+    variables.set("ptr", 0x40010).unwrap();
+    let symbols = Symbols::parse("main = $40100\n");
+    let lines = LineMap::default();
+    let ctx = Context { settings: &settings, variables: &variables, symbols: &symbols, lines: &lines, last_disassembly: None };
+    for (text, expected) in [
+        ("$40000", Some(0x40000)),
+        ("$ptr", Some(0x40010)),
+        ("main", Some(0x40100)),
+        ("$nothing", None),
+        ("nothing", None),
+    ] {
+        let tokens = tokenize(text);
+        let mut tokens = tokens.into_iter().peekable();
+        assert_eq!(parse_number(&mut tokens, &ctx), expected, "{:?}", text);
+        // only a number is consumed
+        assert_eq!(tokens.next().is_some(), expected.is_none(), "{:?}", text);
+    }
+}
+
+#[cfg(test)]
+fn parse_with(line: &str, symbols: &Symbols, last_disassembly: Option<(u32, u32)>) -> Result<Cmd, ParseError> {
+    let settings = Settings::default();
+    let variables = Variables::default();
+    let lines = LineMap::default();
+    parse_cmd(&mut tokenize(line).into_iter().peekable(),
+              &Context { settings: &settings, variables: &variables, symbols, lines: &lines, last_disassembly })
+}
+
+#[cfg(test)]
+fn parse(line: &str) -> Result<Cmd, ParseError> {
+    parse_with(line, &Symbols::default(), None)
+}
+
+#[test]
+fn test_parse_cmd() {
+    for (line, expected) in [
+        ("", Ok("End")),
+        ("continue", Ok("Core(Continue)")),
+        ("pause", Ok("Core(Pause)")),
+        ("step", Ok("Core(Step)")),
+        ("step 5", Ok("UiStep(5)")),
+        ("next", Ok("Core(StepOver)")),
+        ("trace on", Ok("Core(SetTrace(true))")),
+        ("trace off", Ok("Core(SetTrace(false))")),
+        ("triggers", Ok("Core(ListTriggers)")),
+        ("state", Ok("Core(GetState)")),
+        ("mem $40000", Ok("Core(GetMemory { start: 262144, len: 16 })")),
+        ("mem $40000 $20", Ok("Core(GetMemory { start: 262144, len: 32 })")),
+        ("delete $40000", Ok("Core(DeleteTrigger(262144))")),
+        ("delete", Ok("UiDeleteAll")),
+        ("break-adl-change", Ok("UiBreakAdlChange")),
+        ("dis16 $40000 $40010", Ok("Core(Disassemble { adl: Some(false), start: 262144, end: 262160 })")),
+        ("dis24", Ok("Core(DisassemblePc { adl: Some(true) })")),
+        ("\"hello\"", Ok("Core(Message(\"\\\"hello\\\"\"))")),
+        ("trace", Err(ParseError::Usage("expected 'on' or 'off'".to_string()))),
+        ("mem", Err(ParseError::Usage("mem <start> [len] [vdu]".to_string()))),
+        ("step 5 6", Err(ParseError::UnexpectedToken("6".to_string()))),
+        ("frobnicate", Err(ParseError::UnknownCommand("frobnicate".to_string()))),
+        ("mem $40100..$40000", Err(ParseError::Invalid("Invalid range: $40100..$40000 (the end must be after the start)".to_string()))),
+    ] {
+        assert_eq!(parse(line).map(|cmd| format!("{:?}", cmd)), expected.map(str::to_string), "{:?}", line);
+    }
+}
+
+#[test]
+fn test_command_prefixes() {
+    assert!(matches!(parse("cont"), Ok(Cmd::Core(DebugCmd::Continue))));
+    assert!(matches!(parse("s"), Ok(Cmd::Core(DebugCmd::Step))));
+    assert!(matches!(parse("cls"), Ok(Cmd::UiClear)));
+    assert!(matches!(parse(". regs"), Ok(Cmd::UiStateRegs)));
+    assert!(matches!(parse("state full"), Ok(Cmd::UiStateFull)));
+    assert!(matches!(parse("disas $40000"), Ok(Cmd::Core(DebugCmd::Disassemble { adl: None, start: 0x40000, end: 0x40020 }))));
+    assert_eq!(parse("st").unwrap_err().to_string(), "Ambiguous command 'st': state, step, step-branch, step-to-write, struct");
+    assert_eq!(parse("bogus").unwrap_err(), ParseError::UnknownCommand("bogus".to_string()));
+    assert_eq!(parse("bogus").unwrap_err().to_string(), "Unknown command: bogus");
+    assert_eq!(parse("cont now").unwrap_err(), ParseError::UnexpectedToken("now".to_string()));
+}
+
+#[test]
+fn test_memory() {
+    assert!(matches!(parse("mem $40000..$40100"), Ok(Cmd::Core(DebugCmd::GetMemory { start: 0x40000, len: 0x100 }))));
+    assert!(matches!(parse("mem $40000 64 vdu"), Ok(Cmd::UiMemoryVdu { start: 0x40000, len: 64 })));
+    assert!(matches!(parse("mem-wait $40000 $a5"), Ok(Cmd::UiMemWait { address: 0x40000, value: 0xa5 })));
+    assert!(parse("mem-wait $40000 $100").is_err());
+    assert_eq!(parse("mem-wait $40000").unwrap_err(), ParseError::Usage("mem-wait <address> <value>".to_string()));
+}
+
+#[test]
+fn test_examine() {
+    assert!(matches!(parse("x $40000 $555555 w"), Ok(Cmd::UiExamine { start: 0x40000, count: 0x555555, unit: 3 })));
+    assert!(parse("x $40000 $555556 w").is_err());
+    assert!(parse("x $40000 $55555556 w").is_err());
+}
+
+#[test]
+fn test_fix_checksum() {
+    assert!(matches!(parse("fix-checksum $40000 16 $40010 crc32"),
+                     Ok(Cmd::UiFixChecksum { start: 0x40000, len: 16, field: 0x40010, algorithm: Algorithm::Crc32 })));
+    assert!(parse("fix-checksum $40000 16 $40010 md5").is_err());
+}
+
+#[test]
+fn test_vdp_dump() {
+    assert!(matches!(parse("vdp-dump \"a.ppm\" $40000 $1000 $1000 rgb222"), Ok(Cmd::UiVdpDump { width: 0x1000, height: 0x1000, .. })));
+    assert!(parse("vdp-dump \"a.ppm\" $40000 $1000 $1000").is_err());
+    assert!(parse("vdp-dump \"a.ppm\" $40000 $10000 $10000 rgb222").is_err());
+}
+
+#[test]
+fn test_triggers() {
+    assert!(matches!(parse("trigger $40000 \"hi\" : cont"), Ok(Cmd::Core(DebugCmd::AddTrigger(_)))));
+    assert!(matches!(parse("trigger $40000 pause : state : cont"),
+                     Ok(Cmd::Core(DebugCmd::AddTrigger(t)))
+                     if matches!(&t.actions[..], [DebugCmd::Message(m), DebugCmd::Pause, DebugCmd::GetState, DebugCmd::Continue]
+                                 if m == AUTO_CONTINUE_MESSAGE)));
+    assert!(matches!(parse("trigger $40000 pause : state"),
+                     Ok(Cmd::Core(DebugCmd::AddTrigger(t))) if t.actions.len() == 2));
+}
+
+#[test]
+fn test_breakpoints() {
+    assert!(matches!(parse("br $40000 $40010 show dis"), Ok(Cmd::UiBreakpoints(ts)) if ts.len() == 2));
+    assert!(matches!(parse("break 8"), Ok(Cmd::Core(DebugCmd::AddTrigger(_)))));
+    assert!(matches!(parse("toggle $40000"), Ok(Cmd::UiToggle(0x40000))));
+}
+
+#[test]
+fn test_conditional_breakpoints() {
+    assert!(matches!(parse("br $40000 after 50"),
+                     Ok(Cmd::UiConditionalBreak { after: 50, from: None, show_dis: false, .. })));
+    assert!(matches!(parse("br $40000 $40010 from $41234 show dis"),
+                     Ok(Cmd::UiConditionalBreak { addresses, after: 1, from: Some(0x41234), show_dis: true })
+                     if addresses.len() == 2));
+    assert!(parse("br $40000 after 0").is_err());
+    assert!(parse("br after 5").is_err());
+}
+
+#[test]
+fn test_break_at_pc() {
+    assert!(matches!(parse("br"), Ok(Cmd::UiBreakAtPc { offset: 0 })));
+    assert!(matches!(parse("br +8"), Ok(Cmd::UiBreakAtPc { offset: 8 })));
+    assert!(matches!(parse("break PC-$10"), Ok(Cmd::UiBreakAtPc { offset: -16 })));
+    assert!(matches!(parse("break pc"), Ok(Cmd::UiBreakAtPc { offset: 0 })));
+}
+
+#[test]
+fn test_time() {
+    assert!(matches!(parse("time $40000 $40100"), Ok(Cmd::UiTime { start: 0x40000, end: 0x40100, timeout_ms: 10_000 })));
+}
+
+#[test]
+fn test_find_string() {
+    assert!(matches!(parse("find-string \"Hello world\" nocase"),
+                     Ok(Cmd::UiFindString { text, start: 0x40000, end: 0xc0000, nocase: true, context: false })
+                     if text == "Hello world"));
+    assert!(matches!(parse("find-string Hi $40000..$40100 context"),
+                     Ok(Cmd::UiFindString { start: 0x40000, end: 0x40100, nocase: false, context: true, .. })));
+    assert!(matches!(parse("find-string Hi $50000 $50010"), Ok(Cmd::UiFindString { start: 0x50000, end: 0x50010, .. })));
+    assert!(parse("find-string \"\"").is_err());
+    assert!(parse("find-string Hi $50000").is_err());
+}
+
+#[test]
+fn test_watch_range() {
+    assert!(matches!(parse("watch-range $40000 $40100"), Ok(Cmd::UiWatchRange { start: 0x40000, end: 0x40100 })));
+    assert!(matches!(parse("watch-range $40000..$40010"), Ok(Cmd::UiWatchRange { start: 0x40000, end: 0x40010 })));
+    assert!(parse("watch-range $40100 $40000").is_err());
+}
+
+#[test]
+fn test_mark_data() {
+    assert!(matches!(parse("mark-data $40100 $40140"), Ok(Cmd::UiMarkData(Some((0x40100, 0x40140))))));
+    assert!(matches!(parse("mark-data off"), Ok(Cmd::UiMarkData(None))));
+    assert!(parse("mark-data $40140 $40100").is_err());
+}
+
+#[test]
+fn test_confirmation() {
+    assert!(parse("delete").unwrap().confirmation().is_none());
+    assert!(parse("delete $40000").unwrap().confirmation().is_none());
+    assert!(parse("dis $40000 $48000").unwrap().confirmation().is_some());
+    assert!(parse("dis $40000 $48000 bare").unwrap().confirmation().is_some());
+    assert!(parse("dis $40000 $40100").unwrap().confirmation().is_none());
+}
+
+#[test]
+fn test_info_topics() {
+    assert_eq!(parse("info bogus").unwrap_err().to_string(),
+               "Unknown info topic 'bogus'. Topics: all-registers, breakpoints, cpu, data, line, logpoints, skip, variables, vdp");
+    assert!(parse("info").unwrap_err().to_string().starts_with("info <topic>. Topics: all-registers"));
+}
+
+#[test]
+fn test_disassemble_continues() {
+    assert!(matches!(parse("dis"), Ok(Cmd::Core(DebugCmd::DisassemblePc { adl: None }))));
+    assert!(matches!(parse_with("dis", &Symbols::default(), Some((0x40000, 0x40021))),
+                     Ok(Cmd::Core(DebugCmd::Disassemble { adl: None, start: 0x40021, end: 0x40041 }))));
+    assert!(matches!(parse_with("dis16 -", &Symbols::default(), Some((0x40000, 0x40021))),
+                     Ok(Cmd::Core(DebugCmd::Disassemble { adl: Some(false), start: 0x3ffe0, end: 0x40000 }))));
+    assert!(parse("dis -").is_err());
+    // listings stop at the top of the 24-bit address space
+    assert!(matches!(parse("dis $fffff0"), Ok(Cmd::Core(DebugCmd::Disassemble { start: 0xfffff0, end: 0xffffff, .. }))));
+    assert!(matches!(parse_with("dis", &Symbols::default(), Some((0xffffe0, 0xfffff0))),
+                     Ok(Cmd::Core(DebugCmd::Disassemble { start: 0xfffff0, end: 0xffffff, .. }))));
+}
+
+#[test]
+fn test_disassemble_options() {
+    assert!(matches!(parse("dis $40000 clip"), Ok(Cmd::UiDisassemble(_, DisasmOptions { clip: true, .. }))));
+    assert!(parse("dis $40100..$40000").is_err());
+}
+
+#[test]
+fn test_disassemble_symbols() {
+    let symbols = Symbols::parse("main = $40000\nparse = $40100\nloop = $40180\n");
+    let parse = |line: &str| parse_with(line, &symbols, None);
+    assert!(matches!(parse("dis main"), Ok(Cmd::Core(DebugCmd::Disassemble { start: 0x40000, end: 0x40100, .. }))));
+    assert!(matches!(parse("dis main loop"), Ok(Cmd::Core(DebugCmd::Disassemble { start: 0x40000, end: 0x40180, .. }))));
+    assert!(matches!(parse("dis loop"), Ok(Cmd::Core(DebugCmd::Disassemble { start: 0x40180, end: 0x401a0, .. }))));
+    assert!(matches!(parse("dis $40100"), Ok(Cmd::Core(DebugCmd::Disassemble { start: 0x40100, end: 0x40120, .. }))));
+}
+
+#[test]
+fn test_help_topics() {
+    assert!(matches!(parse("help"), Ok(Cmd::UiHelp(None))));
+    match parse("help br") {
+        Ok(Cmd::UiHelp(Some(text))) => {
+            assert!(text.starts_with("break <address>"));
+            assert!(text.ends_with("Aliases: br"));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(parse("help nonsense").is_err());
+}
@@ -0,0 +1,97 @@
+//! Recording of debugger sessions: the commands typed, the requests sent to
+//! the CPU and the responses it gave, each stamped with the time since the
+//! recording started.
+//!
+//! Each line of a recording is `<seconds> <kind> <text>`, where kind is `$`
+//! for a debugger command, `>` for a request to the CPU and `<` for a
+//! response from it. Replaying runs the `$` lines again.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender};
+use agon_cpu_emulator::debugger::{DebugCmd, DebugResp};
+
+pub struct Recorder {
+    file: std::fs::File,
+    started: std::time::Instant,
+}
+
+/// The recording in progress, if any, shared with the channel proxies
+pub type Recording = Arc<Mutex<Option<Recorder>>>;
+
+impl Recorder {
+    pub fn create(path: &str) -> std::io::Result<Recorder> {
+        Ok(Recorder { file: std::fs::File::create(path)?, started: std::time::Instant::now() })
+    }
+
+    fn log(&mut self, kind: char, text: &str) {
+        let _ = writeln!(self.file, "{:.3} {} {}", self.started.elapsed().as_secs_f64(), kind, text);
+    }
+}
+
+pub fn log(recording: &Recording, kind: char, text: &str) {
+    if let Some(recorder) = recording.lock().unwrap().as_mut() {
+        recorder.log(kind, text);
+    }
+}
+
+/// Put proxies between the debugger and the CPU's channels, so everything
+/// passing through them is written to the recording while one is running
+pub fn interpose(tx: Sender<DebugCmd>, rx: Receiver<DebugResp>, recording: &Recording) -> (Sender<DebugCmd>, Receiver<DebugResp>) {
+    let (proxy_tx, cmds) = std::sync::mpsc::channel::<DebugCmd>();
+    let (resps, proxy_rx) = std::sync::mpsc::channel::<DebugResp>();
+    let r = recording.clone();
+    std::thread::spawn(move || {
+        for cmd in cmds {
+            log(&r, '>', &format!("{:?}", cmd));
+            if tx.send(cmd).is_err() { break }
+        }
+    });
+    let r = recording.clone();
+    std::thread::spawn(move || {
+        for resp in rx {
+            log(&r, '<', &describe(&resp));
+            if resps.send(resp).is_err() { break }
+        }
+    });
+    (proxy_tx, proxy_rx)
+}
+
+/// A one-line summary of a response from the CPU
+pub fn describe(resp: &DebugResp) -> String {
+    match resp {
+        DebugResp::Memory { start, data } => format!("Memory &{:06x} {}", start, hex(data)),
+        DebugResp::Message(s) => format!("Message {:?}", s),
+        DebugResp::IsPaused(p) => format!("IsPaused {}", p),
+        DebugResp::Triggers(ts) => format!("Triggers {}", ts.iter()
+            .map(|t| format!("&{:06x}", t.address)).collect::<Vec<_>>().join(" ")),
+        DebugResp::Pong => "Pong".to_string(),
+        DebugResp::Disassembly { pc, adl, disasm } => format!("Disassembly pc=&{:06x} adl={} {}", pc, adl,
+            disasm.iter().map(|d| format!("&{:06x}:{}", d.loc, d.asm)).collect::<Vec<_>>().join("; ")),
+        DebugResp::State { registers, stack, pc_instruction, .. } => format!("State pc=&{:06x} adl={} stack={} {}",
+            registers.pc, registers.adl, hex(stack), pc_instruction),
+        DebugResp::Registers(registers) => format!("Registers pc=&{:06x} adl={}", registers.pc, registers.adl),
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The debugger commands in a recording, in the order they were typed.
+/// Recording and replay commands themselves are left out.
+pub fn commands(text: &str) -> Vec<String> {
+    text.lines().filter_map(|line| {
+        let (_time, rest) = line.split_once(' ')?;
+        let cmd = rest.strip_prefix("$ ")?;
+        let first = cmd.split_whitespace().next().unwrap_or("");
+        (first != "record" && first != "replay").then(|| cmd.to_string())
+    }).collect()
+}
+
+#[test]
+fn test_commands() {
+    let text = "0.000 $ break &40000\n0.001 > AddTrigger(Trigger)\n0.002 < Pong\n\
+                1.500 $ record off\n2.250 $ regs\n";
+    assert_eq!(commands(text), vec!["break &40000".to_string(), "regs".to_string()]);
+}
@@ -0,0 +1,235 @@
+//! Register names, widths and values: for validating register writes,
+//! logpoint messages and info all-registers.
+
+use agon_cpu_emulator::debugger::{ Registers, Reg16 };
+
+/// Width in bits of a register. Register pairs, IX, IY, SP and PC are 24-bit
+/// in ADL mode and 16-bit in Z80 mode.
+pub fn width(name: &str, adl: bool) -> Option<u32> {
+    let mode_width = if adl { 24 } else { 16 };
+    match name.to_ascii_lowercase().as_str() {
+        "a" | "f" | "b" | "c" | "d" | "e" | "h" | "l" |
+        "ixh" | "ixl" | "iyh" | "iyl" | "mb" => Some(8),
+        "af" | "sps" => Some(16),
+        "spl" => Some(24),
+        "bc" | "de" | "hl" | "ix" | "iy" | "sp" | "pc" => Some(mode_width),
+        _ => None
+    }
+}
+
+/// How `reg <register> <op>= <value>` changes a register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Adjust { Add, Sub, And, Or, Xor }
+
+impl Adjust {
+    pub fn from_token(token: &str) -> Option<Adjust> {
+        Some(match token {
+            "+=" => Adjust::Add,
+            "-=" => Adjust::Sub,
+            "&=" => Adjust::And,
+            "|=" => Adjust::Or,
+            "^=" => Adjust::Xor,
+            _ => return None
+        })
+    }
+
+    /// Apply the adjustment, wrapping around within a `bits`-wide register
+    pub fn apply(self, current: u32, operand: u32, bits: u32) -> u32 {
+        let value = match self {
+            Adjust::Add => current.wrapping_add(operand),
+            Adjust::Sub => current.wrapping_sub(operand),
+            Adjust::And => current & operand,
+            Adjust::Or => current | operand,
+            Adjust::Xor => current ^ operand,
+        };
+        value & ((1 << bits) - 1)
+    }
+}
+
+/// Check that `value` fits in the register, rather than silently spilling
+/// into its neighbours
+pub fn validate(name: &str, value: u32, adl: bool) -> Result<(), String> {
+    let bits = width(name, adl).ok_or(format!("Unknown register: {}", name))?;
+    if value >> bits != 0 {
+        let mode = match name.to_ascii_lowercase().as_str() {
+            "bc" | "de" | "hl" | "ix" | "iy" | "sp" | "pc" => {
+                if adl { " in ADL mode" } else { " in Z80 mode" }
+            }
+            _ => ""
+        };
+        return Err(format!("{} is {}-bit{}; value ${:x} exceeds range",
+                           name.to_ascii_uppercase(), bits, mode, value));
+    }
+    Ok(())
+}
+
+/// The value of the named register, with 8-bit halves taken from the
+/// register pairs
+pub fn value(reg: &Registers, name: &str) -> Option<u32> {
+    let pair = |r: Reg16| if reg.adl { reg.get24(r) } else { reg.get16(r) as u32 };
+    Some(match name.to_ascii_lowercase().as_str() {
+        "a" => (reg.get16(Reg16::AF) >> 8) as u32,
+        "f" => (reg.get16(Reg16::AF) & 0xff) as u32,
+        "b" => (reg.get16(Reg16::BC) >> 8) as u32,
+        "c" => (reg.get16(Reg16::BC) & 0xff) as u32,
+        "d" => (reg.get16(Reg16::DE) >> 8) as u32,
+        "e" => (reg.get16(Reg16::DE) & 0xff) as u32,
+        "h" => (reg.get16(Reg16::HL) >> 8) as u32,
+        "l" => (reg.get16(Reg16::HL) & 0xff) as u32,
+        "ixh" => (reg.get16(Reg16::IX) >> 8) as u32,
+        "ixl" => (reg.get16(Reg16::IX) & 0xff) as u32,
+        "iyh" => (reg.get16(Reg16::IY) >> 8) as u32,
+        "iyl" => (reg.get16(Reg16::IY) & 0xff) as u32,
+        "mb" => reg.mbase as u32,
+        "af" => reg.get16(Reg16::AF) as u32,
+        "sps" => reg.get16(Reg16::SP) as u32,
+        "spl" => reg.get24(Reg16::SP),
+        "bc" => pair(Reg16::BC),
+        "de" => pair(Reg16::DE),
+        "hl" => pair(Reg16::HL),
+        "ix" => pair(Reg16::IX),
+        "iy" => pair(Reg16::IY),
+        "sp" => pair(Reg16::SP),
+        "pc" => reg.pc,
+        _ => return None
+    })
+}
+
+/// The flags set in F, as "SZ-H-PNC" with '-' for those clear. Bits 3 and 5
+/// are undocumented and shown as '.'.
+pub fn flags(f: u8) -> String {
+    "SZ.H.PNC".chars().enumerate().map(|(i, name)| {
+        let set = f & (0x80 >> i) != 0;
+        match name {
+            '.' => '.',
+            name if set => name,
+            _ => '-',
+        }
+    }).collect()
+}
+
+/// Registers shown by 'registers verbose', in groups
+const VERBOSE_GROUPS: &[(&str, &[&str])] = &[
+    ("Main", &["a", "f", "b", "c", "d", "e", "h", "l", "bc", "de", "hl"]),
+    ("Index", &["ix", "iy", "ixh", "ixl", "iyh", "iyl"]),
+    ("Stack", &["sp", "sps", "spl"]),
+    ("Control", &["pc", "mb"]),
+];
+
+/// One register as a labelled line, in hex, decimal and, for 8-bit
+/// registers, binary
+fn describe(name: &str, value: u32, bits: u32) -> String {
+    let hex = format!("${:0digits$x}", value, digits = bits as usize / 4);
+    let binary = if bits == 8 { format!("%{:08b}", value) } else { String::new() };
+    format!("  {:4} {:8} {:<8} {}", name.to_ascii_uppercase(), hex, value, binary).trim_end().to_string()
+}
+
+/// Every register on its own line, grouped, followed by the mode flags
+pub fn print_verbose(reg: &Registers) {
+    for (group, names) in VERBOSE_GROUPS {
+        println!("{}:", group);
+        for name in names.iter() {
+            if let Some((bits, value)) = width(name, reg.adl).zip(value(reg, name)) {
+                println!("{}", describe(name, value, bits));
+            }
+        }
+    }
+    println!("Flags:   {}", flags(value(reg, "f").unwrap_or(0) as u8));
+    println!("Mode:    ADL {}  MADL {}  IFF1 {}", reg.adl as u8, reg.madl as u8, reg.get_iff1() as u8);
+}
+
+/// Every register the emulator exposes, one line per register pair
+pub fn print_all(reg: &Registers) {
+    let pair_width = if reg.adl { 6 } else { 4 };
+    let v = |name: &str| value(reg, name).unwrap_or(0);
+    println!("PC   {:06x}", reg.pc);
+    println!("ADL  {}  MADL {}  MB {:02x}  IFF1 {}", reg.adl as u8, reg.madl as u8, reg.mbase, reg.get_iff1() as u8);
+    println!("AF   {:6}  A   {:02x}  F   {:02x}  flags {}", format!("{:04x}", v("af")), v("a"), v("f"), flags(v("f") as u8));
+    for (pair, hi, lo) in [("bc", "b", "c"), ("de", "d", "e"), ("hl", "h", "l"), ("ix", "ixh", "ixl"), ("iy", "iyh", "iyl")] {
+        println!("{:4} {:6}  {:3} {:02x}  {:3} {:02x}", pair.to_ascii_uppercase(), format!("{:0w$x}", v(pair), w = pair_width),
+                 hi.to_ascii_uppercase(), v(hi), lo.to_ascii_uppercase(), v(lo));
+    }
+    println!("SPS  {:04x}", v("sps"));
+    println!("SPL  {:06x}", v("spl"));
+}
+
+/// Replace each %NAME in `template` with the register's value in hex, eg:
+/// "A=%A HL=%HL". %% is a literal %, and unknown names are left alone.
+pub fn substitute(template: &str, reg: &Registers) -> String {
+    substitute_with(template, |name| {
+        let bits = width(name, reg.adl)?;
+        Some(format!("{:0digits$x}", value(reg, name)?, digits = bits as usize / 4))
+    })
+}
+
+fn substitute_with(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(i) = rest.find('%') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('%') {
+            out.push('%');
+            rest = after;
+            continue;
+        }
+        let len = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+        match lookup(&rest[..len]) {
+            Some(value) => out.push_str(&value),
+            None => {
+                out.push('%');
+                out.push_str(&rest[..len]);
+            }
+        }
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[test]
+fn test_describe() {
+    assert_eq!(describe("a", 0x41, 8), "  A    $41      65       %01000001");
+    assert_eq!(describe("hl", 0x40000, 24), "  HL   $040000  262144");
+}
+
+#[test]
+fn test_flags() {
+    assert_eq!(flags(0xff), "SZ.H.PNC");
+    assert_eq!(flags(0x41), "-Z.-.--C");
+}
+
+#[test]
+fn test_substitute() {
+    let lookup = |name: &str| match name.to_ascii_lowercase().as_str() {
+        "a" => Some("12".to_string()),
+        "hl" => Some("040000".to_string()),
+        _ => None
+    };
+    assert_eq!(substitute_with("A=%A HL=%hl, 100%%", lookup), "A=12 HL=040000, 100%");
+    assert_eq!(substitute_with("%XY %", lookup), "%XY %");
+}
+
+#[test]
+fn test_validate() {
+    assert_eq!(validate("a", 0x12345, true), Err("A is 8-bit; value $12345 exceeds range".to_string()));
+    assert_eq!(validate("A", 0xff, true), Ok(()));
+    assert_eq!(validate("af", 0x1ff00, true), Err("AF is 16-bit; value $1ff00 exceeds range".to_string()));
+    assert_eq!(validate("hl", 0x40000, true), Ok(()));
+    assert_eq!(validate("hl", 0x40000, false), Err("HL is 16-bit in Z80 mode; value $40000 exceeds range".to_string()));
+    assert_eq!(validate("sp", 0xffff, false), Ok(()));
+    assert_eq!(validate("spl", 0x1000000, true), Err("SPL is 24-bit; value $1000000 exceeds range".to_string()));
+    assert!(validate("xy", 0, true).is_err());
+}
+
+#[test]
+fn test_adjust() {
+    assert_eq!(Adjust::from_token("+="), Some(Adjust::Add));
+    assert_eq!(Adjust::from_token("="), None);
+    assert_eq!(Adjust::Add.apply(0x40000, 4, 24), 0x40004);
+    assert_eq!(Adjust::Sub.apply(0, 1, 8), 0xff);
+    assert_eq!(Adjust::Add.apply(0xfffe, 4, 16), 2);
+    assert_eq!(Adjust::Xor.apply(0x41, 0x01, 8), 0x40);
+    assert_eq!(Adjust::Or.apply(0x40, 0x80, 8), 0xc0);
+    assert_eq!(Adjust::And.apply(0xff, 0x0f, 8), 0x0f);
+}
@@ -0,0 +1,493 @@
+//! Debugger options, changed with `set <name> <value>`.
+
+use crate::parser::{ number_from_str, ADDRESS_SPACE_LEN };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisasmSyntax {
+    /// As produced by the emulator's disassembler
+    Native,
+    /// Upper-case, with hex numbers written 0FFh
+    Zilog,
+    /// Lower-case, with hex numbers written 0xff
+    Gnu,
+}
+
+/// How disassembly is cased, after 'disasm-syntax'
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisasmCase {
+    Keep,
+    Upper,
+    Lower,
+}
+
+/// Where disassembly listings show each instruction's opcode bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisasmBytes {
+    /// After the instruction
+    On,
+    Off,
+    /// In a fixed-width column before the instruction
+    Left,
+}
+
+/// What step and next show after each instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutput {
+    /// The full CPU state
+    Full,
+    /// Just PC and the instruction there
+    Compact,
+    None,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// Combine bytes into a word in this byte order
+    pub fn word(self, bytes: &[u8]) -> u32 {
+        let fold = |acc: u32, &b: &u8| (acc << 8) | b as u32;
+        match self {
+            Endian::Little => bytes.iter().rev().fold(0, fold),
+            Endian::Big => bytes.iter().fold(0, fold),
+        }
+    }
+}
+
+pub struct Settings {
+    /// Show addresses as 4 hex digits in Z80 mode, instead of always 6
+    pub address_width_auto: bool,
+    /// Ring the terminal bell when a breakpoint is hit
+    pub bell: bool,
+    /// Ask before commands that throw away state, such as deleting all
+    /// breakpoints
+    pub confirm: bool,
+    /// While stepping, watch the code shown by disassembly for writes
+    pub detect_smc: bool,
+    pub disasm_bytes: DisasmBytes,
+    pub disasm_case: DisasmCase,
+    pub disasm_syntax: DisasmSyntax,
+    /// Show the current value at the address an instruction reads or writes
+    pub disasm_values: bool,
+    /// Default length of a memory dump, in bytes
+    pub dumpsize: u32,
+    /// Names of the stack words of a call frame, from SP upwards
+    pub frame_layout: Vec<String>,
+    /// Don't add a command to the history if it repeats the previous one
+    pub history_dedup: bool,
+    /// Most commands kept in the history
+    pub history_size: u32,
+    /// Print each command before running it, for transcripts of scripts
+    pub input_echo: bool,
+    /// Default length of a disassembly listing, in bytes
+    pub listsize: u32,
+    /// Reuse memory already read while the CPU is paused
+    pub mem_read_cache: bool,
+    /// Shell command run when a breakpoint is hit
+    pub notify_command: Option<String>,
+    /// Mark the instruction at PC in listings with => rather than *
+    pub pc_arrow: bool,
+    /// In Z80 mode, also show the physical address PC refers to
+    pub physical_pc: bool,
+    /// How long to wait for the CPU to answer a command, in milliseconds
+    pub response_timeout: u32,
+    /// Address ranges (start, end exclusive) whose calls step steps over
+    pub skip_ranges: Vec<(u32, u32)>,
+    /// Bytes of stack shown with the CPU state, or None for as many as the
+    /// CPU sends
+    pub stack_bytes: Option<u32>,
+    /// Show the top of the stack with the CPU state
+    pub state_stack: bool,
+    pub step_output: StepOutput,
+    /// Byte order used to show words with `x`. The eZ80 itself is little-endian.
+    pub word_endian: Endian,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            address_width_auto: false,
+            bell: false,
+            confirm: true,
+            detect_smc: false,
+            disasm_bytes: DisasmBytes::On,
+            disasm_case: DisasmCase::Keep,
+            disasm_syntax: DisasmSyntax::Native,
+            disasm_values: false,
+            dumpsize: 16,
+            frame_layout: vec!["ret".to_string()],
+            history_dedup: true,
+            history_size: 100,
+            input_echo: false,
+            listsize: 0x20,
+            mem_read_cache: false,
+            notify_command: None,
+            pc_arrow: false,
+            physical_pc: false,
+            response_timeout: 10000,
+            skip_ranges: vec![],
+            stack_bytes: None,
+            state_stack: true,
+            step_output: StepOutput::Full,
+            word_endian: Endian::Little,
+        }
+    }
+}
+
+/// Names of all the settings, in the order show lists them
+pub const NAMES: &[&str] = &[
+    "address-width", "bell", "confirm", "detect-smc", "disasm-bytes", "disasm-case", "disasm-syntax", "disasm-values", "dumpsize", "frame-layout",
+    "history-dedup", "history-size", "input-echo", "listsize", "mem-read-cache", "notify-command", "pc-arrow", "physical-pc",
+    "response-timeout", "skip-range", "stack-bytes", "state-stack", "step-output", "word-endian",
+];
+
+impl Settings {
+    /// The current value of a setting, written the way set takes it
+    pub fn get(&self, name: &str) -> Result<String, String> {
+        let on_off = |b: bool| if b { "on" } else { "off" }.to_string();
+        Ok(match name {
+            "address-width" => if self.address_width_auto { "auto" } else { "24" }.to_string(),
+            "bell" => on_off(self.bell),
+            "confirm" => on_off(self.confirm),
+            "disasm-bytes" => match self.disasm_bytes {
+                DisasmBytes::On => "on",
+                DisasmBytes::Off => "off",
+                DisasmBytes::Left => "left",
+            }.to_string(),
+            "disasm-case" => match self.disasm_case {
+                DisasmCase::Keep => "keep",
+                DisasmCase::Upper => "upper",
+                DisasmCase::Lower => "lower",
+            }.to_string(),
+            "disasm-syntax" => match self.disasm_syntax {
+                DisasmSyntax::Native => "native",
+                DisasmSyntax::Zilog => "zilog",
+                DisasmSyntax::Gnu => "gnu",
+            }.to_string(),
+            "detect-smc" => on_off(self.detect_smc),
+            "disasm-values" => on_off(self.disasm_values),
+            "dumpsize" => self.dumpsize.to_string(),
+            "frame-layout" => self.frame_layout.join(","),
+            "history-dedup" => on_off(self.history_dedup),
+            "history-size" => self.history_size.to_string(),
+            "input-echo" => on_off(self.input_echo),
+            "listsize" => format!("${:x}", self.listsize),
+            "mem-read-cache" => on_off(self.mem_read_cache),
+            "notify-command" => self.notify_command.clone().unwrap_or("off".to_string()),
+            "pc-arrow" => on_off(self.pc_arrow),
+            "physical-pc" => on_off(self.physical_pc),
+            "response-timeout" => self.response_timeout.to_string(),
+            "skip-range" if self.skip_ranges.is_empty() => "off".to_string(),
+            "skip-range" => self.skip_ranges.iter()
+                .map(|(start, end)| format!("${:06x} ${:06x}", start, end))
+                .collect::<Vec<String>>()
+                .join(", "),
+            "stack-bytes" => self.stack_bytes.map_or("auto".to_string(), |n| n.to_string()),
+            "state-stack" => on_off(self.state_stack),
+            "step-output" => match self.step_output {
+                StepOutput::Full => "full",
+                StepOutput::Compact => "compact",
+                StepOutput::None => "none",
+            }.to_string(),
+            "word-endian" => match self.word_endian {
+                Endian::Little => "little",
+                Endian::Big => "big",
+            }.to_string(),
+            _ => return Err(format!("Unknown setting: {}", name))
+        })
+    }
+
+    /// Write the settings that differ from the defaults to `path`, as
+    /// `<name> <value>` lines, which load reads back. Comments and lines
+    /// that aren't settings are kept from the file already there, and
+    /// settings stay where they were in it.
+    pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
+        let existing = std::fs::read_to_string(path).unwrap_or_default();
+        std::fs::write(path, self.save_text(&existing))
+    }
+
+    /// The text save writes over `existing`
+    fn save_text(&self, existing: &str) -> String {
+        let defaults = Settings::default();
+        let mut written = vec![];
+        let mut text = String::new();
+        let mut write_setting = |name: &'static str, text: &mut String| {
+            if written.contains(&name) {
+                return;
+            }
+            written.push(name);
+            if self.get(name) == defaults.get(name) {
+                return;
+            }
+            if name == "skip-range" {
+                // one line per range, as each set adds a range
+                for (start, end) in &self.skip_ranges {
+                    text.push_str(&format!("skip-range ${:06x} ${:06x}\n", start, end));
+                }
+            } else {
+                text.push_str(&format!("{} {}\n", name, self.get(name).unwrap()));
+            }
+        };
+        for line in existing.lines() {
+            let name = line.split_whitespace().next().unwrap_or("");
+            let name = if name == "confirm-delete" { "confirm" } else { name };
+            match NAMES.iter().find(|&&n| n == name) {
+                Some(&name) => write_setting(name, &mut text),
+                None => {
+                    text.push_str(line);
+                    text.push('\n');
+                }
+            }
+        }
+        for &name in NAMES {
+            write_setting(name, &mut text);
+        }
+        text
+    }
+
+    /// Apply settings saved by save. Lines that can't be applied are
+    /// skipped and reported in the result.
+    pub fn load(&mut self, text: &str) -> Vec<String> {
+        let mut errors = vec![];
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+            if let Err(e) = self.set(name, value.trim()) {
+                errors.push(e);
+            }
+        }
+        errors
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "address-width" => {
+                self.address_width_auto = match value {
+                    "24" => false,
+                    "auto" => true,
+                    _ => return Err("address-width must be '24' or 'auto'".to_string())
+                };
+            }
+            "bell" => self.bell = parse_bool(name, value)?,
+            // confirm-delete is the older name, from before confirm covered
+            // more than delete
+            "confirm" | "confirm-delete" => self.confirm = parse_bool(name, value)?,
+            "disasm-bytes" => {
+                self.disasm_bytes = match value {
+                    "on" => DisasmBytes::On,
+                    "off" => DisasmBytes::Off,
+                    "left" => DisasmBytes::Left,
+                    _ => return Err("disasm-bytes must be one of: on, off, left".to_string())
+                };
+            }
+            "disasm-case" => {
+                self.disasm_case = match value {
+                    "keep" => DisasmCase::Keep,
+                    "upper" => DisasmCase::Upper,
+                    "lower" => DisasmCase::Lower,
+                    _ => return Err("disasm-case must be one of: keep, upper, lower".to_string())
+                };
+            }
+            "disasm-syntax" => {
+                self.disasm_syntax = match value {
+                    "native" => DisasmSyntax::Native,
+                    "zilog" => DisasmSyntax::Zilog,
+                    "gnu" => DisasmSyntax::Gnu,
+                    _ => return Err("disasm-syntax must be one of: native, zilog, gnu".to_string())
+                };
+            }
+            "detect-smc" => self.detect_smc = parse_bool(name, value)?,
+            "disasm-values" => self.disasm_values = parse_bool(name, value)?,
+            "dumpsize" => self.dumpsize = parse_size(name, value)?,
+            "frame-layout" => {
+                self.frame_layout = value.split(',').map(|name| name.to_string()).collect();
+            }
+            "history-dedup" => self.history_dedup = parse_bool(name, value)?,
+            "history-size" => self.history_size = parse_count(name, value)?,
+            "input-echo" => self.input_echo = parse_bool(name, value)?,
+            "listsize" => self.listsize = parse_size(name, value)?,
+            "mem-read-cache" => self.mem_read_cache = parse_bool(name, value)?,
+            "notify-command" => self.notify_command = (value != "off").then(|| value.to_string()),
+            "pc-arrow" => self.pc_arrow = parse_bool(name, value)?,
+            "physical-pc" => self.physical_pc = parse_bool(name, value)?,
+            "response-timeout" => self.response_timeout = parse_count(name, value)?,
+            "skip-range" => {
+                if value == "off" {
+                    self.skip_ranges.clear();
+                } else {
+                    let range: Vec<Option<u32>> = value.split_whitespace().map(number_from_str).collect();
+                    match range[..] {
+                        [Some(start), Some(end)] if start < end => self.skip_ranges.push((start, end)),
+                        _ => return Err("skip-range must be '<start> <end>' or 'off'".to_string())
+                    }
+                }
+            }
+            "stack-bytes" => {
+                self.stack_bytes = if value == "auto" { None } else { Some(parse_stack_bytes(name, value)?) };
+            }
+            "state-stack" => self.state_stack = parse_bool(name, value)?,
+            "step-output" => {
+                self.step_output = match value {
+                    "full" => StepOutput::Full,
+                    "compact" => StepOutput::Compact,
+                    "none" => StepOutput::None,
+                    _ => return Err("step-output must be one of: full, compact, none".to_string())
+                };
+            }
+            "word-endian" => {
+                self.word_endian = match value {
+                    "little" => Endian::Little,
+                    "big" => Endian::Big,
+                    _ => return Err("word-endian must be 'little' or 'big'".to_string())
+                };
+            }
+            _ => return Err(format!("Unknown setting: {}", name))
+        }
+        Ok(())
+    }
+
+    pub fn in_skip_range(&self, addr: u32) -> bool {
+        self.skip_ranges.iter().any(|&(start, end)| (start..end).contains(&addr))
+    }
+
+    /// Format PC, followed in Z80 mode by the physical address MB:PC when
+    /// 'physical-pc' is on
+    pub fn pc(&self, pc: u32, mbase: u8, adl: bool) -> String {
+        if self.physical_pc && !adl {
+            format!("{} (phys={:02x}:{:04x})", self.address(pc, adl), mbase, pc & 0xffff)
+        } else {
+            self.address(pc, adl)
+        }
+    }
+
+    /// Format a code address: 6 hex digits, or with 'address-width auto' the
+    /// 4 digits of its 16-bit offset in Z80 mode
+    pub fn address(&self, addr: u32, adl: bool) -> String {
+        if self.address_width_auto && !adl {
+            format!("{:04x}", addr & 0xffff)
+        } else {
+            format!("{:06x}", addr)
+        }
+    }
+}
+
+fn parse_size(name: &str, value: &str) -> Result<u32, String> {
+    match number_from_str(value) {
+        Some(n) if n > 0 && n <= ADDRESS_SPACE_LEN => Ok(n),
+        _ => Err(format!("{} must be a number of bytes, up to ${:x}", name, ADDRESS_SPACE_LEN))
+    }
+}
+
+/// Most stack bytes the CPU state can be asked to show
+const MAX_STACK_BYTES: u32 = 256;
+
+fn parse_stack_bytes(name: &str, value: &str) -> Result<u32, String> {
+    match number_from_str(value) {
+        Some(n) if n > 0 && n <= MAX_STACK_BYTES => Ok(n),
+        _ => Err(format!("{} must be 'auto' or a number of bytes, up to {}", name, MAX_STACK_BYTES))
+    }
+}
+
+fn parse_count(name: &str, value: &str) -> Result<u32, String> {
+    match number_from_str(value) {
+        Some(n) if n > 0 => Ok(n),
+        _ => Err(format!("{} must be a positive number", name))
+    }
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("{} must be 'on' or 'off'", name))
+    }
+}
+
+#[test]
+fn test_address() {
+    let mut settings = Settings::default();
+    assert_eq!(settings.address(0x040123, false), "040123");
+    settings.set("address-width", "auto").unwrap();
+    assert_eq!(settings.address(0x040123, false), "0123");
+    assert_eq!(settings.address(0x040123, true), "040123");
+    settings.set("physical-pc", "on").unwrap();
+    assert_eq!(settings.pc(0x0123, 0x04, false), "0123 (phys=04:0123)");
+    assert_eq!(settings.pc(0x040123, 0x04, true), "040123");
+}
+
+#[test]
+fn test_skip_range() {
+    let mut settings = Settings::default();
+    settings.set("skip-range", "$0 $40000").unwrap();
+    settings.set("skip-range", "$50000 $50100").unwrap();
+    assert!(settings.in_skip_range(0x1234));
+    assert!(!settings.in_skip_range(0x40000));
+    assert!(settings.in_skip_range(0x500ff));
+    assert!(settings.set("skip-range", "$10").is_err());
+    settings.set("skip-range", "off").unwrap();
+    assert!(!settings.in_skip_range(0x1234));
+}
+
+#[test]
+fn test_get_and_load() {
+    let mut settings = Settings::default();
+    settings.set("disasm-bytes", "left").unwrap();
+    settings.set("skip-range", "$0 $40000").unwrap();
+    settings.set("skip-range", "$50000 $50100").unwrap();
+    assert_eq!(settings.get("disasm-bytes"), Ok("left".to_string()));
+    assert_eq!(settings.get("skip-range"), Ok("$000000 $040000, $050000 $050100".to_string()));
+    assert_eq!(settings.get("listsize"), Ok("$20".to_string()));
+    assert!(settings.set("listsize", "$1000000").is_ok());
+    assert!(settings.set("listsize", "$ffffffff").is_err());
+    assert!(settings.get("bogus").is_err());
+    assert_eq!(settings.get("stack-bytes"), Ok("auto".to_string()));
+    settings.set("stack-bytes", "$30").unwrap();
+    assert_eq!(settings.get("stack-bytes"), Ok("48".to_string()));
+    assert!(settings.set("stack-bytes", "0").is_err());
+    assert!(settings.set("stack-bytes", "256").is_ok());
+    assert!(settings.set("stack-bytes", "257").is_err());
+    assert!(settings.set("stack-bytes", "$80000000").is_err());
+
+    let mut loaded = Settings::default();
+    let errors = loaded.load("disasm-bytes left\nskip-range $0 $40000\n\n# comment\nbogus 1\nconfirm-delete off\n");
+    assert_eq!(errors, vec!["Unknown setting: bogus".to_string()]);
+    assert_eq!(loaded.disasm_bytes, DisasmBytes::Left);
+    assert!(!loaded.confirm);
+    assert!(loaded.in_skip_range(0x1234));
+    for &name in NAMES {
+        assert!(Settings::default().get(name).is_ok());
+    }
+}
+
+#[test]
+fn test_endian_word() {
+    assert_eq!(Endian::Little.word(&[0x56, 0x34, 0x12]), 0x123456);
+    assert_eq!(Endian::Big.word(&[0x12, 0x34]), 0x1234);
+}
+
+#[test]
+fn test_save_and_load() {
+    let mut settings = Settings::default();
+    settings.set("disasm-case", "upper").unwrap();
+    settings.set("notify-command", "notify-send 'CPU paused'").unwrap();
+    settings.set("skip-range", "$0 $40000").unwrap();
+    settings.set("skip-range", "$50000 $50100").unwrap();
+    settings.set("stack-bytes", "12").unwrap();
+    let text = settings.save_text("");
+    assert_eq!(text, "disasm-case upper\nnotify-command notify-send 'CPU paused'\n\
+                      skip-range $000000 $040000\nskip-range $050000 $050100\nstack-bytes 12\n");
+    let mut loaded = Settings::default();
+    assert_eq!(loaded.load(&text), Vec::<String>::new());
+    for &name in NAMES {
+        assert_eq!(loaded.get(name), settings.get(name), "{}", name);
+    }
+
+    // comments and other lines stay, settings stay in place, and those back
+    // at their defaults go
+    let existing = "# my settings\nstack-bytes 6\nbogus 1\n\nconfirm-delete off\nbell on\n";
+    assert_eq!(settings.save_text(existing),
+               "# my settings\nstack-bytes 12\nbogus 1\n\ndisasm-case upper\nnotify-command notify-send 'CPU paused'\n\
+                skip-range $000000 $040000\nskip-range $050000 $050100\n");
+}
@@ -0,0 +1,79 @@
+//! Symbol tables, loaded from assembler symbol or map files.
+
+use std::collections::BTreeMap;
+use crate::parser::number_from_str;
+
+#[derive(Default)]
+pub struct Symbols {
+    by_addr: BTreeMap<u32, String>,
+}
+
+impl Symbols {
+    /// Parse a symbol file. Each line names one symbol, in any of the forms
+    /// `name = $40000`, `name: equ $40000`, `name $40000` or `$40000 name`.
+    /// Lines that do not look like a symbol definition are ignored.
+    pub fn parse(text: &str) -> Symbols {
+        let mut symbols = Symbols::default();
+        for line in text.lines() {
+            let words: Vec<&str> = line
+                .split(|c: char| c.is_whitespace() || c == '=' || c == ':' || c == ',')
+                .filter(|w| !w.is_empty() && !w.eq_ignore_ascii_case("equ") && !w.eq_ignore_ascii_case(".equ"))
+                .collect();
+            let (name, addr) = match words[..] {
+                [name, value] if is_symbol_name(name) && parse_address(value).is_some() => {
+                    (name, parse_address(value))
+                }
+                [value, name] if is_symbol_name(name) => (name, parse_address(value)),
+                _ => continue
+            };
+            if let Some(addr) = addr {
+                symbols.by_addr.insert(addr, name.to_string());
+            }
+        }
+        symbols
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_addr.len()
+    }
+
+    pub fn address(&self, name: &str) -> Option<u32> {
+        self.by_addr.iter().find(|(_, n)| n.as_str() == name).map(|(&addr, _)| addr)
+    }
+
+    /// The symbol at or before `addr`, and the address of the next symbol
+    /// after it, if any
+    pub fn containing(&self, addr: u32) -> Option<(u32, &str, Option<u32>)> {
+        let (&start, name) = self.by_addr.range(..=addr).next_back()?;
+        let next = self.by_addr.range(addr + 1..).next().map(|(&a, _)| a);
+        Some((start, name, next))
+    }
+}
+
+fn is_symbol_name(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.') &&
+        word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn parse_address(word: &str) -> Option<u32> {
+    match word.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => number_from_str(word),
+    }
+}
+
+#[test]
+fn test_parse_symbols() {
+    let symbols = Symbols::parse("\
+main = $40000
+init: EQU 040100h
+; a comment
+loop 0x40180
+$40200 _exit
+");
+    assert_eq!(symbols.len(), 4);
+    assert_eq!(symbols.containing(0x40150), Some((0x40100, "init", Some(0x40180))));
+    assert_eq!(symbols.containing(0x40200), Some((0x40200, "_exit", None)));
+    assert_eq!(symbols.containing(0x3ffff), None);
+    assert_eq!(symbols.address("loop"), Some(0x40180));
+}
@@ -0,0 +1,164 @@
+//! Rendering of Agon graphics data held in eZ80 memory.
+
+use crate::parser::ADDRESS_SPACE_LEN;
+
+#[derive(Clone, Copy, Debug)]
+pub enum PixelFormat {
+    /// 4 bytes per pixel, as uploaded with VDU 23,27,1
+    Rgba8888,
+    /// 1 byte per pixel, 2 bits each of red, green and blue (bits 0-1, 2-3, 4-5)
+    Rgb222,
+}
+
+impl PixelFormat {
+    pub fn from_name(name: &str) -> Option<PixelFormat> {
+        match name {
+            "rgba" => Some(PixelFormat::Rgba8888),
+            "rgb222" => Some(PixelFormat::Rgb222),
+            _ => None
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Rgba8888 => 4,
+            PixelFormat::Rgb222 => 1,
+        }
+    }
+}
+
+/// Bytes of pixel data in a `width` x `height` image, or None if that is
+/// more than the eZ80's address space could hold
+pub fn data_len(width: u32, height: u32, format: PixelFormat) -> Option<u32> {
+    width.checked_mul(height)?.checked_mul(format.bytes_per_pixel()).filter(|&len| len <= ADDRESS_SPACE_LEN)
+}
+
+/// Convert raw pixel data to a binary PPM (P6) image. Pixels missing from
+/// the end of `data` are black. None if the image is too big for `data_len`.
+pub fn to_ppm(data: &[u8], width: u32, height: u32, format: PixelFormat) -> Option<Vec<u8>> {
+    let pixels = data_len(width, height, format)? / format.bytes_per_pixel();
+    let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    let image_len = ppm.len() + pixels as usize * 3;
+    for pixel in data.chunks_exact(format.bytes_per_pixel() as usize) {
+        match format {
+            PixelFormat::Rgba8888 => ppm.extend_from_slice(&pixel[0..3]),
+            PixelFormat::Rgb222 => {
+                for shift in [0, 2, 4] {
+                    ppm.push(((pixel[0] >> shift) & 3) * 0x55);
+                }
+            }
+        }
+    }
+    ppm.resize(image_len, 0);
+    Some(ppm)
+}
+
+/// Short names of the VDU control codes, with how many argument bytes
+/// follow each. VDU 23 commands vary in length on the Agon; the 8 bytes of
+/// the standard BBC BASIC form are assumed.
+const VDU_CODES: &[(u8, &str, usize)] = &[
+    (0, "NUL", 0), (1, "PRINTER-CHAR", 1), (2, "PRINTER-ON", 0), (3, "PRINTER-OFF", 0),
+    (4, "TEXT-AT-TEXT", 0), (5, "TEXT-AT-GFX", 0), (6, "ENABLE", 0), (7, "BELL", 0),
+    (8, "LEFT", 0), (9, "RIGHT", 0), (10, "LF", 0), (11, "UP", 0),
+    (12, "CLS", 0), (13, "CR", 0), (14, "PAGE-ON", 0), (15, "PAGE-OFF", 0),
+    (16, "CLG", 0), (17, "COLOUR", 1), (18, "GCOL", 2), (19, "PALETTE", 5),
+    (20, "RESET-COLOURS", 0), (21, "DISABLE", 0), (22, "MODE", 1), (23, "VDU23", 8),
+    (24, "GFX-VIEWPORT", 8), (25, "PLOT", 5), (26, "RESET-VIEWPORTS", 0), (27, "ESC", 1),
+    (28, "TEXT-VIEWPORT", 4), (29, "ORIGIN", 4), (30, "HOME", 0), (31, "TAB", 2),
+    (127, "DEL", 0),
+];
+
+/// Name the VDU commands in a stream of bytes, eg: "MODE 3", giving the
+/// offset each starts at. Printable text is left to the ASCII column.
+pub fn vdu_annotations(data: &[u8]) -> Vec<(usize, String)> {
+    let mut notes = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        match VDU_CODES.iter().find(|(code, _, _)| *code == data[i]) {
+            Some(&(_, name, num_args)) => {
+                let args: Vec<String> = data[i + 1..].iter().take(num_args).map(|b| b.to_string()).collect();
+                notes.push((i, format!("{} {}", name, args.join(",")).trim_end().to_string()));
+                i += 1 + num_args;
+            }
+            None => i += 1,
+        }
+    }
+    notes
+}
+
+/// Offsets of the screen details MOS keeps in its system variables, as
+/// returned by mos_sysvars (see mos_api.inc)
+const SYSVAR_CURSOR_X: usize = 0x07;
+const SYSVAR_CURSOR_Y: usize = 0x08;
+const SYSVAR_SCR_WIDTH: usize = 0x0f;
+const SYSVAR_SCR_HEIGHT: usize = 0x11;
+const SYSVAR_SCR_COLS: usize = 0x13;
+const SYSVAR_SCR_ROWS: usize = 0x14;
+const SYSVAR_SCR_COLOURS: usize = 0x15;
+const SYSVAR_SCR_MODE: usize = 0x27;
+
+/// Bytes of system variables needed by describe_screen
+pub const SYSVARS_LEN: u32 = SYSVAR_SCR_MODE as u32 + 1;
+
+/// The video mode and screen details from MOS's system variables. MOS
+/// updates them from the VDP's replies, so they lag a mode change briefly.
+pub fn describe_screen(sysvars: &[u8]) -> Vec<String> {
+    let byte = |offset: usize| sysvars.get(offset).copied().unwrap_or(0);
+    let word = |offset: usize| byte(offset) as u32 | (byte(offset + 1) as u32) << 8;
+    vec![
+        format!("Mode     {}", byte(SYSVAR_SCR_MODE)),
+        format!("Screen   {}x{} pixels, {}x{} characters", word(SYSVAR_SCR_WIDTH), word(SYSVAR_SCR_HEIGHT),
+                byte(SYSVAR_SCR_COLS), byte(SYSVAR_SCR_ROWS)),
+        format!("Colours  {}", byte(SYSVAR_SCR_COLOURS)),
+        format!("Cursor   {},{}", byte(SYSVAR_CURSOR_X), byte(SYSVAR_CURSOR_Y)),
+    ]
+}
+
+#[test]
+fn test_to_ppm() {
+    assert_eq!(to_ppm(&[0x03, 0x3c], 2, 1, PixelFormat::Rgb222).unwrap(),
+               b"P6\n2 1\n255\n\xff\x00\x00\x00\xff\xff");
+    assert_eq!(to_ppm(&[1, 2, 3, 255], 1, 1, PixelFormat::Rgba8888).unwrap(),
+               b"P6\n1 1\n255\n\x01\x02\x03");
+    assert_eq!(to_ppm(&[0x30], 2, 1, PixelFormat::Rgb222).unwrap(),
+               b"P6\n2 1\n255\n\x00\x00\xff\x00\x00\x00");
+    assert_eq!(to_ppm(&[], 0x10000, 0x10000, PixelFormat::Rgb222), None);
+    assert_eq!(to_ppm(&[], 0x1000, 0x1000, PixelFormat::Rgba8888), None);
+}
+
+#[test]
+fn test_data_len() {
+    assert_eq!(data_len(16, 16, PixelFormat::Rgba8888), Some(1024));
+    assert_eq!(data_len(0x1000, 0x1000, PixelFormat::Rgb222), Some(0x1000000));
+    assert_eq!(data_len(0x1000, 0x1000, PixelFormat::Rgba8888), None);
+    assert_eq!(data_len(0x10000, 0x10000, PixelFormat::Rgb222), None);
+}
+
+#[test]
+fn test_describe_screen() {
+    let mut sysvars = vec![0; SYSVARS_LEN as usize];
+    sysvars[SYSVAR_SCR_WIDTH..SYSVAR_SCR_WIDTH + 4].copy_from_slice(&[0x80, 0x02, 0xe0, 0x01]);
+    sysvars[SYSVAR_SCR_COLS] = 80;
+    sysvars[SYSVAR_SCR_ROWS] = 60;
+    sysvars[SYSVAR_SCR_COLOURS] = 16;
+    sysvars[SYSVAR_SCR_MODE] = 3;
+    sysvars[SYSVAR_CURSOR_X] = 5;
+    assert_eq!(describe_screen(&sysvars), vec![
+        "Mode     3",
+        "Screen   640x480 pixels, 80x60 characters",
+        "Colours  16",
+        "Cursor   5,0",
+    ]);
+}
+
+#[test]
+fn test_vdu_annotations() {
+    assert_eq!(vdu_annotations(&[22, 3, b'H', b'i', 17, 15, 13, 10]), vec![
+        (0, "MODE 3".to_string()),
+        (4, "COLOUR 15".to_string()),
+        (6, "CR".to_string()),
+        (7, "LF".to_string()),
+    ]);
+    // a command cut off by the end of the data shows the arguments there are
+    assert_eq!(vdu_annotations(&[31, 5]), vec![(0, "TAB 5".to_string())]);
+}